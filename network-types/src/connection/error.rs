@@ -0,0 +1,104 @@
+use std::fmt;
+
+/// Why a buffer could not be turned into a [`Packet`](super::Packet).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecodeError {
+    /// The buffer ended before a whole packet was read.
+    Truncated,
+    /// The variant tag does not match any packet known to this revision.
+    UnknownVariant,
+    /// A string field is not valid UTF-8.
+    InvalidUtf8,
+    /// The frame announces more bytes than the receiver accepts.
+    Oversized { size: usize, max: usize },
+    /// Any other malformed content.
+    Malformed(String),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::Truncated => write!(f, "truncated frame"),
+            DecodeError::UnknownVariant => write!(f, "unknown packet variant"),
+            DecodeError::InvalidUtf8 => write!(f, "invalid UTF-8 in string field"),
+            DecodeError::Oversized { size, max } => {
+                write!(
+                    f,
+                    "frame of {} bytes exceeds the limit of {} bytes",
+                    size, max
+                )
+            }
+            DecodeError::Malformed(reason) => write!(f, "malformed packet: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+impl From<postcard::Error> for DecodeError {
+    fn from(err: postcard::Error) -> Self {
+        match err {
+            postcard::Error::DeserializeUnexpectedEnd => DecodeError::Truncated,
+            // Tags past the last variant are refused by the derived visitor,
+            // which postcard reports as a custom error without its message
+            postcard::Error::DeserializeBadEnum | postcard::Error::SerdeDeCustom => {
+                DecodeError::UnknownVariant
+            }
+            postcard::Error::DeserializeBadUtf8 | postcard::Error::DeserializeBadChar => {
+                DecodeError::InvalidUtf8
+            }
+            err => DecodeError::Malformed(err.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connection::{Credentials, Packet};
+
+    fn login(name: &str) -> Vec<u8> {
+        Packet::LoginRequest {
+            name: name.to_owned(),
+            credentials: Credentials::None,
+        }
+        .serialize()
+    }
+
+    /// Why `buffer` is no packet, if it is not.
+    fn decode(buffer: &[u8]) -> Option<DecodeError> {
+        Packet::try_from(buffer).err()
+    }
+
+    #[test]
+    fn short_buffers_are_truncated() {
+        assert_eq!(decode(&[]), Some(DecodeError::Truncated));
+        let buffer = login("fred");
+        assert_eq!(
+            decode(&buffer[..buffer.len() - 2]),
+            Some(DecodeError::Truncated)
+        );
+    }
+
+    #[test]
+    fn unknown_tags_are_unknown_variants() {
+        // Varint tag 1000, far beyond the last variant
+        assert_eq!(decode(&[0xe8, 0x07]), Some(DecodeError::UnknownVariant));
+    }
+
+    #[test]
+    fn broken_strings_are_invalid_utf8() {
+        let mut buffer = login("fred");
+        let at = buffer.iter().position(|b| *b == b'f').unwrap();
+        buffer[at] = 0xff;
+        assert_eq!(decode(&buffer), Some(DecodeError::InvalidUtf8));
+    }
+
+    #[test]
+    fn valid_packets_decode() {
+        assert!(matches!(
+            Packet::try_from(&login("fred")),
+            Ok(Packet::LoginRequest { name, credentials: Credentials::None }) if name == "fred"
+        ));
+    }
+}
@@ -1,21 +1,243 @@
-use std::error::Error;
+mod error;
+mod request;
 
+pub use error::DecodeError;
+pub use request::RequestTracker;
+
+/// Revision of the [`Packet`] layout. Bump it whenever a variant is added,
+/// removed or changes its fields.
+pub const PROTOCOL_VERSION: u16 = 23;
+
+/// Size of the big-endian length prefix written by
+/// [`Packet::serialize_with_header`].
+pub const FRAME_HEADER_SIZE: usize = 4;
+
+/// Why one side is closing the connection.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisconnectReason {
+    /// The peer sent something that is not a valid packet for its state.
+    ProtocolViolation,
+    /// The peer announced a frame larger than the receiver accepts.
+    FrameTooLarge,
+    /// Nothing was received from the peer within the heartbeat timeout.
+    Timeout,
+    /// The peer did not read fast enough and its outbound queue filled up.
+    SlowConsumer,
+    /// The server already serves as many clients as it is allowed to.
+    ServerFull,
+    /// The server is going away.
+    ServerShutdown,
+    /// The peer had too many logins refused.
+    TooManyAttempts,
+}
+
+/// Proof of identity sent with [`Packet::LoginRequest`]. Which kinds are
+/// accepted depends on how the server is set up.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum Credentials {
+    /// Nothing, for servers that let anybody in.
+    None,
+    /// Password of an account known to the server.
+    Password(String),
+    /// Signed token issued by the game's backend.
+    Token(String),
+}
+
+/// Who can find and join a match.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Visibility {
+    /// Listed in [`Packet::MatchList`] and joinable by id.
+    #[default]
+    Public,
+    /// Left out of the match list, joinable by anybody who knows the id.
+    Unlisted,
+    /// Left out of the match list and only joinable with its
+    /// [`Packet::JoinByCode`] code.
+    Private,
+}
+
+/// Settings picked by the owner when creating a match.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq, Eq, Default)]
+pub struct MatchOptions {
+    /// Most members at once, owner included. `None` takes the server's
+    /// limit.
+    pub max_players: Option<u32>,
+    pub visibility: Visibility,
+    /// Password [`Packet::JoinMatch`] has to give.
+    pub password: Option<String>,
+    /// Free-form labels players can filter the match list by, such as a
+    /// game mode.
+    pub tags: Vec<String>,
+}
+
+/// Whether a listed match is still gathering players.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MatchState {
+    Waiting,
+    Started,
+}
+
+/// One entry of [`Packet::MatchList`].
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct MatchInfo {
+    pub id: i32,
+    pub name: String,
+    pub host: String,
+    /// Known once the match has started.
+    pub map: Option<String>,
+    pub state: MatchState,
+    pub players: i32,
+    pub max_players: i32,
+    /// Whether joining takes a password.
+    pub locked: bool,
+    pub tags: Vec<String>,
+    /// Where the server runs, for players to pick one close to them.
+    pub region: String,
+}
+
+/// Order of the entries in [`Packet::MatchList`].
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MatchSort {
+    #[default]
+    Oldest,
+    Newest,
+    MostPlayers,
+    FewestPlayers,
+    Name,
+}
+
+/// Which public matches [`Packet::ListMatches`] asks for. The default asks
+/// for the first page of all of them.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq, Eq, Default)]
+pub struct MatchQuery {
+    pub not_started: bool,
+    pub has_free_slots: bool,
+    /// Only matches whose name contains this, ignoring case.
+    pub name_contains: Option<String>,
+    /// Only matches carrying all of these tags.
+    pub tags: Vec<String>,
+    pub sort: MatchSort,
+    /// Matching entries to skip.
+    pub offset: u32,
+    /// Most entries to send, `0` or more than the server allows takes the
+    /// server's page size.
+    pub limit: u32,
+}
+
+/// Client request a [`Packet::Error`] answers.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RequestKind {
+    Login,
+    ListMatches,
+    NewMatch,
+    JoinMatch,
+    LeaveMatch,
+    DeleteMatch,
+    StartMatch,
+    SpawnPlayers,
+    RegenerateJoinCode,
+}
+
+impl RequestKind {
+    /// The request `packet` makes, if it is one the server answers.
+    pub fn of(packet: &Packet) -> Option<Self> {
+        match packet {
+            Packet::LoginRequest { .. } => Some(RequestKind::Login),
+            Packet::ListMatches { .. } | Packet::RemoveFromListMatches => {
+                Some(RequestKind::ListMatches)
+            }
+            Packet::NewMatch { .. } => Some(RequestKind::NewMatch),
+            Packet::JoinMatch { .. } | Packet::JoinByCode { .. } => Some(RequestKind::JoinMatch),
+            Packet::LeaveMatch { .. } => Some(RequestKind::LeaveMatch),
+            Packet::DeleteMatch { .. } => Some(RequestKind::DeleteMatch),
+            Packet::StartMatch { .. } => Some(RequestKind::StartMatch),
+            Packet::SpawnPlayers { .. } => Some(RequestKind::SpawnPlayers),
+            Packet::RegenerateJoinCode { .. } => Some(RequestKind::RegenerateJoinCode),
+            _ => None,
+        }
+    }
+}
+
+/// Stable failure codes carried by [`Packet::Error`]. Clients match on these,
+/// so existing codes keep their position and new ones are appended.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    /// The request is malformed or makes no sense in the current state.
+    InvalidRequest,
+    /// No match with the given id exists.
+    RoomNotFound,
+    /// The match has already started.
+    RoomStarted,
+    /// Only the match owner may do this.
+    NotHost,
+    /// The client is not a member of the match.
+    NotInRoom,
+    /// The client is already a member of the match.
+    AlreadyInRoom,
+    /// The request is not allowed in the client's current session state.
+    InvalidState,
+    /// The server is overloaded and turned the request down, it may be retried.
+    ServerBusy,
+    /// The server already runs as many matches as it is allowed to.
+    RoomLimitReached,
+    /// The server is shutting down and takes no new matches or players.
+    ShuttingDown,
+    /// The server is going down for maintenance and takes no new matches or
+    /// players, running matches go on.
+    Draining,
+    /// The login credentials were refused.
+    AuthenticationFailed,
+    /// The requested name breaks the server's naming rules, or belongs to an
+    /// account somebody else is logged in as.
+    InvalidName,
+    /// The match has as many members as it takes.
+    RoomFull,
+    /// The match is password protected and the password given is wrong.
+    WrongPassword,
+    /// The client tried too many invite codes that do not exist and may not
+    /// try more from its address for a while, or too many wrong match
+    /// passwords and may not try any more on this connection.
+    TooManyAttempts,
+}
+
+/// Packets exchanged over the TCP and UDP connections.
+///
+/// postcard encodes variants by position, so new variants must only be
+/// appended. `Hello`, `Welcome` and `HelloRejected` must keep their position
+/// and fields forever: they are how peers built against different revisions
+/// find out they cannot talk to each other.
 #[derive(serde::Serialize, serde::Deserialize, Debug)]
 pub enum Packet {
+    /// Heartbeat. The server sends one every heartbeat interval and clients
+    /// answer with a `Ping` of their own; the server never answers pings.
     Ping,
-    Disconnect,
+    Disconnect {
+        reason: DisconnectReason,
+        message: String,
+    },
     LoginRequest {
         name: String,
+        credentials: Credentials,
     },
     Login {
         id: i32,
         name: String,
     },
-    ListMatches,
+    /// Asks for the match list and subscribes to its updates, replacing any
+    /// earlier subscription. The [`Packet::MatchList`] answer is followed by
+    /// [`Packet::MatchAdded`], [`Packet::MatchUpdated`] and
+    /// [`Packet::MatchRemoved`] for every match passing the query's filters,
+    /// whatever the page asked for.
+    ListMatches {
+        query: MatchQuery,
+    },
+    /// Stops the updates started by [`Packet::ListMatches`]. Joining a match
+    /// or disconnecting does the same.
     RemoveFromListMatches,
     MatchDeleted,
     NewMatch {
         room_name: String,
+        options: MatchOptions,
     },
     DeleteMatch {
         room_id: i32,
@@ -24,6 +246,8 @@ pub enum Packet {
         id: i32,
         owner_id: i32,
         room_name: String,
+        /// Code friends join the match with, see [`Packet::JoinByCode`].
+        join_code: String,
     },
     MatchJoined {
         id: i32,
@@ -37,12 +261,19 @@ pub enum Packet {
     },
     JoinMatch {
         room_id: i32,
+        password: Option<String>,
     },
     LeaveMatch {
         room_id: i32,
     },
     MatchList {
-        matches: Vec<(i32, String, i32)>,
+        /// The requested page.
+        matches: Vec<MatchInfo>,
+        /// Public matches matching the query, on all pages.
+        total: u32,
+        /// The server is going down for maintenance and takes no new matches
+        /// or players.
+        draining: bool,
     },
     StartMatch {
         room_id: i32,
@@ -83,15 +314,139 @@ pub enum Packet {
         name: String,
         text: String,
     },
+    Hello {
+        protocol_version: u16,
+        client_version: String,
+        capabilities: Vec<String>,
+    },
+    Welcome {
+        protocol_version: u16,
+        server_version: String,
+        capabilities: Vec<String>,
+    },
+    HelloRejected {
+        protocol_version: u16,
+        reason: String,
+    },
+    Error {
+        request: RequestKind,
+        code: ErrorCode,
+        message: String,
+    },
+    /// Client request tagged with an id the server echoes in its reply.
+    Request {
+        request_id: u32,
+        packet: Box<Packet>,
+    },
+    /// Server reply to a [`Packet::Request`] with the same id.
+    Response {
+        request_id: u32,
+        packet: Box<Packet>,
+    },
+    /// Reply to a correlated request that has no other answer.
+    Ack,
+    HostChanged {
+        room_id: i32,
+        new_owner_id: i32,
+    },
+    /// A member of the match logged in again under another name.
+    NameChanged {
+        id: i32,
+        name: String,
+    },
+    /// Sent to every member when the host starts the match, and to clients
+    /// joining it late. `start_at` is the server's Unix time in milliseconds
+    /// at which everybody should begin playing.
+    MatchStarted {
+        room_id: i32,
+        map: String,
+        seed: u64,
+        start_at: u64,
+    },
+    /// Joins the match with this invite code, whatever its visibility. Case,
+    /// spaces and dashes in the code do not matter. Only a few codes that do
+    /// not exist may be tried from an address every ten minutes, see
+    /// [`ErrorCode::TooManyAttempts`].
+    JoinByCode {
+        code: String,
+        password: Option<String>,
+    },
+    /// Host request replacing the match's invite code, so that the old one
+    /// stops working.
+    RegenerateJoinCode {
+        room_id: i32,
+    },
+    /// The match's new invite code, sent to every member. The current code is
+    /// also sent to a member that takes over as host.
+    JoinCode {
+        room_id: i32,
+        code: String,
+    },
+    /// A match now passes the subscription's filters.
+    MatchAdded {
+        info: MatchInfo,
+    },
+    /// A match passing the subscription's filters changed.
+    MatchUpdated {
+        info: MatchInfo,
+    },
+    /// A match ended or no longer passes the subscription's filters.
+    MatchRemoved {
+        id: i32,
+    },
+    /// Sent over TCP to a client entering a match, after the
+    /// [`Packet::MatchCreated`] or [`Packet::MatchJoined`] answering it.
+    /// Sending it back as the first datagram to the UDP relay registers the
+    /// sender's address with the match. The token stops working once the
+    /// client leaves the match.
+    RelayToken {
+        room_id: i32,
+        token: u64,
+    },
 }
 
 impl Packet {
-    pub fn from(buffer: &[u8]) -> Self {
-        postcard::from_bytes(buffer).unwrap()
+    pub fn try_from(buffer: &[u8]) -> Result<Self, DecodeError> {
+        Ok(postcard::from_bytes(buffer)?)
+    }
+
+    /// Decodes one frame written by [`Packet::serialize_with_header`] from the
+    /// start of `buffer`, returning the packet and the number of bytes used,
+    /// or `None` while the frame has not been received in full.
+    ///
+    /// Frames whose payload is larger than `max_payload` are refused as soon
+    /// as their header is in.
+    pub fn from_frame(
+        buffer: &[u8],
+        max_payload: usize,
+    ) -> Result<Option<(Self, usize)>, DecodeError> {
+        let Some(header) = buffer.get(..FRAME_HEADER_SIZE) else {
+            return Ok(None);
+        };
+        let len = u32::from_be_bytes(header.try_into().unwrap()) as usize;
+        if len > max_payload {
+            return Err(DecodeError::Oversized {
+                size: len,
+                max: max_payload,
+            });
+        }
+
+        let Some(payload) = buffer.get(FRAME_HEADER_SIZE..FRAME_HEADER_SIZE + len) else {
+            return Ok(None);
+        };
+        Ok(Some((Self::try_from(payload)?, FRAME_HEADER_SIZE + len)))
     }
 
-    pub fn try_from(buffer: &[u8]) -> Result<Self, postcard::Error> {
-        postcard::from_bytes(buffer)
+    /// Wraps `self` in a [`Packet::Response`] when it answers a correlated
+    /// request, and leaves it as is otherwise.
+    pub fn reply_to(self, request_id: Option<u32>) -> Self {
+        match request_id {
+            Some(request_id) => Packet::Response {
+                request_id,
+                packet: Box::new(self),
+            },
+            None => self,
+        }
     }
 
     pub fn serialize(&self) -> Vec<u8> {
@@ -107,7 +462,7 @@ impl Packet {
         buf.extend_from_slice(&len.to_be_bytes());
         buf.extend_from_slice(&message[..]);
 
-        return buf;
+        buf
     }
 }
 
@@ -0,0 +1,154 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        Condvar, Mutex,
+        atomic::{AtomicU32, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use super::Packet;
+
+/// Client-side bookkeeping for correlated requests.
+///
+/// [`RequestTracker::request`] wraps an outgoing packet in a
+/// [`Packet::Request`] with a fresh id. Every packet read from the server goes
+/// through [`RequestTracker::dispatch`], which keeps the responses to tracked
+/// requests for [`RequestTracker::wait`] and hands everything else (broadcasts
+/// and uncorrelated replies) back to the caller.
+#[derive(Default)]
+pub struct RequestTracker {
+    next_id: AtomicU32,
+    pending: Mutex<HashMap<u32, Option<Packet>>>,
+    ready: Condvar,
+}
+
+impl RequestTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Starts tracking a new request and returns its id together with the
+    /// packet to send.
+    pub fn request(&self, packet: Packet) -> (u32, Packet) {
+        let request_id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.pending.lock().unwrap().insert(request_id, None);
+        (
+            request_id,
+            Packet::Request {
+                request_id,
+                packet: Box::new(packet),
+            },
+        )
+    }
+
+    /// Routes a packet received from the server. Returns the packet back when
+    /// it is not the response to a tracked request. Responses to requests
+    /// that were cancelled or timed out are dropped.
+    pub fn dispatch(&self, packet: Packet) -> Option<Packet> {
+        match packet {
+            Packet::Response { request_id, packet } => {
+                let mut pending = self.pending.lock().unwrap();
+                if let Some(slot) = pending.get_mut(&request_id) {
+                    *slot = Some(*packet);
+                    self.ready.notify_all();
+                }
+                None
+            }
+            packet => Some(packet),
+        }
+    }
+
+    /// Blocks until the response to `request_id` arrives, giving up after
+    /// `timeout`. The request is no longer tracked once this returns.
+    pub fn wait(&self, request_id: u32, timeout: Duration) -> Option<Packet> {
+        let deadline = Instant::now() + timeout;
+        let mut pending = self.pending.lock().unwrap();
+        loop {
+            match pending.get_mut(&request_id) {
+                Some(slot) if slot.is_some() => {
+                    return pending.remove(&request_id).flatten();
+                }
+                Some(_) => {}
+                None => return None,
+            }
+
+            let now = Instant::now();
+            if now >= deadline {
+                pending.remove(&request_id);
+                return None;
+            }
+            pending = self.ready.wait_timeout(pending, deadline - now).unwrap().0;
+        }
+    }
+
+    /// Stops tracking `request_id`; a late response will be dropped.
+    pub fn cancel(&self, request_id: u32) {
+        self.pending.lock().unwrap().remove(&request_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, thread};
+
+    use super::*;
+
+    fn response(request_id: u32, room_id: i32) -> Packet {
+        Packet::Response {
+            request_id,
+            packet: Box::new(Packet::LeaveMatch { room_id }),
+        }
+    }
+
+    #[test]
+    fn request_wraps_packets_with_fresh_ids() {
+        let tracker = RequestTracker::new();
+        let (first, packet) = tracker.request(Packet::Ping);
+        assert!(matches!(
+            packet,
+            Packet::Request { request_id, packet } if request_id == first && matches!(*packet, Packet::Ping)
+        ));
+        let (second, _) = tracker.request(Packet::Ping);
+        assert_ne!(first, second);
+    }
+
+    #[test]
+    fn dispatch_keeps_responses_for_wait() {
+        let tracker = Arc::new(RequestTracker::new());
+        let (request_id, _) = tracker.request(Packet::Ping);
+
+        let waiter = {
+            let tracker = tracker.clone();
+            thread::spawn(move || tracker.wait(request_id, Duration::from_secs(5)))
+        };
+        assert!(tracker.dispatch(response(request_id, 7)).is_none());
+        assert!(matches!(
+            waiter.join().unwrap(),
+            Some(Packet::LeaveMatch { room_id: 7 })
+        ));
+        // Answered requests are no longer tracked
+        assert!(tracker.wait(request_id, Duration::ZERO).is_none());
+    }
+
+    #[test]
+    fn dispatch_hands_back_everything_else() {
+        let tracker = RequestTracker::new();
+        assert!(matches!(tracker.dispatch(Packet::Ping), Some(Packet::Ping)));
+        // Responses nobody waits for are dropped
+        assert!(tracker.dispatch(response(41, 7)).is_none());
+    }
+
+    #[test]
+    fn late_responses_are_dropped() {
+        let tracker = RequestTracker::new();
+        let (timed_out, _) = tracker.request(Packet::Ping);
+        let (cancelled, _) = tracker.request(Packet::Ping);
+
+        assert!(tracker.wait(timed_out, Duration::from_millis(10)).is_none());
+        tracker.cancel(cancelled);
+        tracker.dispatch(response(timed_out, 7));
+        tracker.dispatch(response(cancelled, 7));
+        assert!(tracker.pending.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,382 @@
+use std::{
+    collections::HashMap,
+    fmt, fs, io,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use hmac::{Hmac, KeyInit, Mac};
+use network_types::connection::Credentials;
+use sha2::Sha256;
+
+/// Why a login was refused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthError {
+    /// This kind of credentials is not accepted here.
+    Unsupported,
+    /// Unknown account, wrong password or forged token.
+    Rejected,
+    /// The token is genuine but no longer valid.
+    Expired,
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuthError::Unsupported => write!(f, "these credentials are not accepted"),
+            AuthError::Rejected => write!(f, "invalid credentials"),
+            AuthError::Expired => write!(f, "the token has expired"),
+        }
+    }
+}
+
+impl std::error::Error for AuthError {}
+
+/// Decides who a client logging in is. Runs on one of the server's login
+/// workers, see [`ServerConfig::login_workers`], so a slow check holds up
+/// other logins but never the sockets.
+///
+/// [`ServerConfig::login_workers`]: crate::ServerConfig::login_workers
+pub trait Authenticator: Send + Sync {
+    /// Checks `credentials` for a client asking to be called `name`, and
+    /// returns the name it is known by from now on.
+    fn authenticate(&self, name: &str, credentials: &Credentials) -> Result<String, AuthError>;
+
+    /// Whether the names returned belong to whoever logs in under them. A
+    /// proven name another client is using is refused rather than given a
+    /// number, so that nobody shows up under somebody else's account.
+    fn proves_identity(&self) -> bool {
+        true
+    }
+}
+
+/// Lets anybody in under the name they ask for.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Anonymous;
+
+impl Authenticator for Anonymous {
+    fn authenticate(&self, name: &str, _credentials: &Credentials) -> Result<String, AuthError> {
+        Ok(name.to_owned())
+    }
+
+    fn proves_identity(&self) -> bool {
+        false
+    }
+}
+
+/// Accounts read from a file, one `name:rounds:salt:hash` line each, where
+/// `hash` is the hex PBKDF2-HMAC-SHA256 of the password with the hex `salt`
+/// bytes over `rounds` iterations. Empty lines and lines starting with `#`
+/// are skipped.
+pub struct CredentialsFile {
+    accounts: HashMap<String, Account>,
+    /// Checked against for unknown names, so that they take as long to turn
+    /// down as wrong passwords.
+    dummy: Account,
+}
+
+struct Account {
+    rounds: u32,
+    salt: Vec<u8>,
+    hash: Vec<u8>,
+}
+
+impl Account {
+    fn matches(&self, password: &str) -> bool {
+        constant_time_eq(
+            &hash_password(password, &self.salt, self.rounds),
+            &self.hash,
+        )
+    }
+}
+
+impl CredentialsFile {
+    /// Iterations [`CredentialsFile::entry`] hashes with, a good fraction of a
+    /// second of work per login in release builds.
+    pub const DEFAULT_ROUNDS: u32 = 600_000;
+
+    pub fn load(path: &str) -> io::Result<Self> {
+        Self::parse(&fs::read_to_string(path)?)
+    }
+
+    pub fn parse(text: &str) -> io::Result<Self> {
+        let mut accounts = HashMap::new();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid = || {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("line {}: expected name:rounds:salt:hash", number + 1),
+                )
+            };
+            let mut fields = line.splitn(4, ':');
+            let (Some(name), Some(rounds), Some(salt), Some(hash)) =
+                (fields.next(), fields.next(), fields.next(), fields.next())
+            else {
+                return Err(invalid());
+            };
+            let (Ok(rounds @ 1..), Some(salt), Some(hash)) =
+                (rounds.parse(), decode_hex(salt), decode_hex(hash))
+            else {
+                return Err(invalid());
+            };
+            accounts.insert(name.to_owned(), Account { rounds, salt, hash });
+        }
+        // As slow to check as the slowest account
+        let rounds = accounts
+            .values()
+            .map(|account| account.rounds)
+            .max()
+            .unwrap_or(Self::DEFAULT_ROUNDS);
+        let dummy = Account {
+            rounds,
+            salt: vec![0; 16],
+            hash: vec![0; HASH_LEN],
+        };
+        Ok(Self { accounts, dummy })
+    }
+
+    /// The line to put in the file for `name` logging in with `password`.
+    pub fn entry(name: &str, salt: &[u8], password: &str) -> String {
+        Self::entry_with_rounds(name, salt, password, Self::DEFAULT_ROUNDS)
+    }
+
+    /// Like [`CredentialsFile::entry`], hashing over `rounds` iterations.
+    pub fn entry_with_rounds(name: &str, salt: &[u8], password: &str, rounds: u32) -> String {
+        format!(
+            "{}:{}:{}:{}",
+            name,
+            rounds,
+            encode_hex(salt),
+            encode_hex(&hash_password(password, salt, rounds))
+        )
+    }
+}
+
+impl Authenticator for CredentialsFile {
+    fn authenticate(&self, name: &str, credentials: &Credentials) -> Result<String, AuthError> {
+        let Credentials::Password(password) = credentials else {
+            return Err(AuthError::Unsupported);
+        };
+        match self.accounts.get(name) {
+            Some(account) if account.matches(password) => Ok(name.to_owned()),
+            Some(_) => Err(AuthError::Rejected),
+            None => {
+                self.dummy.matches(password);
+                Err(AuthError::Rejected)
+            }
+        }
+    }
+}
+
+/// Bearer tokens issued by the game's backend, shaped `name.expiry.mac`
+/// where `expiry` is in seconds since the Unix epoch and `mac` is the hex
+/// HMAC-SHA256 of `name.expiry` under the shared secret. The client is known
+/// by the name in the token, whatever it asked for.
+pub struct SignedTokens {
+    secret: Vec<u8>,
+}
+
+impl SignedTokens {
+    pub fn new(secret: impl Into<Vec<u8>>) -> Self {
+        Self {
+            secret: secret.into(),
+        }
+    }
+
+    /// A token for `name` valid until `expiry`, as the backend issues them.
+    pub fn issue(&self, name: &str, expiry: u64) -> String {
+        let payload = format!("{}.{}", name, expiry);
+        let mac = self.mac(&payload).finalize().into_bytes();
+        format!("{}.{}", payload, encode_hex(&mac))
+    }
+
+    fn mac(&self, payload: &str) -> Hmac<Sha256> {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&self.secret).expect("HMAC takes keys of any size");
+        mac.update(payload.as_bytes());
+        mac
+    }
+}
+
+impl Authenticator for SignedTokens {
+    fn authenticate(&self, _name: &str, credentials: &Credentials) -> Result<String, AuthError> {
+        let Credentials::Token(token) = credentials else {
+            return Err(AuthError::Unsupported);
+        };
+        let (payload, signature) = token.rsplit_once('.').ok_or(AuthError::Rejected)?;
+        let signature = decode_hex(signature).ok_or(AuthError::Rejected)?;
+        self.mac(payload)
+            .verify_slice(&signature)
+            .map_err(|_| AuthError::Rejected)?;
+
+        let (name, expiry) = payload.rsplit_once('.').ok_or(AuthError::Rejected)?;
+        let expiry: u64 = expiry.parse().map_err(|_| AuthError::Rejected)?;
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |now| now.as_secs());
+        if now >= expiry {
+            return Err(AuthError::Expired);
+        }
+        Ok(name.to_owned())
+    }
+}
+
+const HASH_LEN: usize = 32;
+
+fn hash_password(password: &str, salt: &[u8], rounds: u32) -> Vec<u8> {
+    let mut hash = vec![0; HASH_LEN];
+    pbkdf2::pbkdf2_hmac::<Sha256>(password.as_bytes(), salt, rounds, &mut hash);
+    hash
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn decode_hex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn password(text: &str) -> Credentials {
+        Credentials::Password(text.to_owned())
+    }
+
+    fn token(text: &str) -> Credentials {
+        Credentials::Token(text.to_owned())
+    }
+
+    fn accounts() -> CredentialsFile {
+        let text = format!(
+            "# players\n\n{}\n{}\n",
+            CredentialsFile::entry_with_rounds("fred", b"salty", "hunter2", 10),
+            CredentialsFile::entry_with_rounds("wilma", b"pepper", "yabba", 20),
+        );
+        CredentialsFile::parse(&text).unwrap()
+    }
+
+    #[test]
+    fn credentials_file_accepts_the_right_password() {
+        let accounts = accounts();
+        assert_eq!(
+            accounts.authenticate("fred", &password("hunter2")),
+            Ok("fred".to_owned())
+        );
+        assert_eq!(
+            accounts.authenticate("wilma", &password("yabba")),
+            Ok("wilma".to_owned())
+        );
+    }
+
+    #[test]
+    fn credentials_file_rejects_wrong_passwords_and_unknown_names() {
+        let accounts = accounts();
+        assert_eq!(
+            accounts.authenticate("fred", &password("yabba")),
+            Err(AuthError::Rejected)
+        );
+        assert_eq!(
+            accounts.authenticate("fred", &password("")),
+            Err(AuthError::Rejected)
+        );
+        assert_eq!(
+            accounts.authenticate("barney", &password("hunter2")),
+            Err(AuthError::Rejected)
+        );
+        assert_eq!(
+            accounts.authenticate("fred", &token("hunter2")),
+            Err(AuthError::Unsupported)
+        );
+    }
+
+    #[test]
+    fn credentials_file_refuses_malformed_lines() {
+        for line in [
+            "fred",
+            "fred:73616c7479:0011",
+            "fred:0:73616c7479:0011",
+            "fred:ten:73616c7479:0011",
+            "fred:10:salty:0011",
+            "fred:10:73616c7479:001",
+        ] {
+            assert!(CredentialsFile::parse(line).is_err(), "{line}");
+        }
+    }
+
+    #[test]
+    fn signed_token_names_the_client() {
+        let tokens = SignedTokens::new("secret");
+        let issued = tokens.issue("fred", u64::MAX);
+        assert_eq!(
+            tokens.authenticate("barney", &token(&issued)),
+            Ok("fred".to_owned())
+        );
+    }
+
+    #[test]
+    fn signed_token_rejects_forgeries() {
+        let tokens = SignedTokens::new("secret");
+        let forged = SignedTokens::new("guess").issue("fred", u64::MAX);
+        assert_eq!(
+            tokens.authenticate("fred", &token(&forged)),
+            Err(AuthError::Rejected)
+        );
+
+        let issued = tokens.issue("fred", u64::MAX);
+        let renamed = issued.replacen("fred", "barney", 1);
+        assert_eq!(
+            tokens.authenticate("barney", &token(&renamed)),
+            Err(AuthError::Rejected)
+        );
+    }
+
+    #[test]
+    fn signed_token_expires() {
+        let tokens = SignedTokens::new("secret");
+        let issued = tokens.issue("fred", 1);
+        assert_eq!(
+            tokens.authenticate("fred", &token(&issued)),
+            Err(AuthError::Expired)
+        );
+    }
+
+    #[test]
+    fn signed_token_rejects_malformed_tokens() {
+        let tokens = SignedTokens::new("secret");
+        let payload = "fred.soon";
+        let mac = encode_hex(&tokens.mac(payload).finalize().into_bytes());
+        for malformed in [
+            String::new(),
+            "fred".to_owned(),
+            "fred.123.not-hex".to_owned(),
+            format!("{}.{}", payload, mac),
+            format!("fred.{}", mac),
+        ] {
+            assert_eq!(
+                tokens.authenticate("fred", &token(&malformed)),
+                Err(AuthError::Rejected),
+                "{malformed:?}"
+            );
+        }
+        assert_eq!(
+            tokens.authenticate("fred", &password("x")),
+            Err(AuthError::Unsupported)
+        );
+    }
+}
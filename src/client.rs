@@ -0,0 +1,351 @@
+use std::{
+    io::{self, Write},
+    net::{SocketAddr, TcpStream},
+    sync::{
+        Arc, RwLock,
+        atomic::{AtomicBool, Ordering},
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use crossbeam::channel::{Receiver, RecvTimeoutError};
+use log::{debug, info, trace, warn};
+use network_types::connection::{DisconnectReason, ErrorCode, Packet, RequestKind};
+
+use crate::{
+    NameRules, OutboundConfig,
+    dispatcher::Dispatcher,
+    helpers,
+    login::Logins,
+    outbound::Outbound,
+    registry::ClientRegistry,
+    session::{ClientState, FrameLimits, Session},
+};
+
+pub(crate) struct Client {
+    pub id: i32,
+    pub peer_addr: SocketAddr,
+    pub match_id: i32,
+    pub state: ClientState,
+    pub name: String,
+    /// Round trip time, once it has been measured.
+    pub latency: Option<Duration>,
+    /// When the unanswered heartbeat was sent.
+    pub ping_sent: Option<Instant>,
+    /// Lets the client into its match's UDP relay, see [`crate::udp::Relay`].
+    pub relay_token: Option<u64>,
+    /// Wrong match passwords tried.
+    pub password_failures: u32,
+    stream: TcpStream,
+    pub outbound: Outbound,
+    running: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+    writer: Option<JoinHandle<()>>,
+}
+
+/// How often the server pings clients, and how long a client may stay silent
+/// before it is dropped.
+#[derive(Debug, Clone, Copy)]
+pub struct Heartbeat {
+    pub interval: Duration,
+    pub timeout: Duration,
+}
+
+impl Default for Heartbeat {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(5),
+            timeout: Duration::from_secs(15),
+        }
+    }
+}
+
+impl Client {
+    pub fn new(
+        id: i32,
+        peer_addr: SocketAddr,
+        stream: TcpStream,
+        outbound: OutboundConfig,
+    ) -> Self {
+        Self {
+            id,
+            peer_addr,
+            stream,
+            outbound: Outbound::new(outbound),
+            match_id: -1,
+            state: ClientState::Handshake,
+            name: String::new(),
+            latency: None,
+            ping_sent: None,
+            relay_token: None,
+            password_failures: 0,
+            running: Arc::new(AtomicBool::new(true)),
+            thread: None,
+            writer: None,
+        }
+    }
+
+    pub fn state_of(id: i32, clients: &Arc<RwLock<ClientRegistry>>) -> Option<ClientState> {
+        clients.read().unwrap().get(id).map(|c| c.state)
+    }
+
+    pub fn outbound_of(id: i32, clients: &Arc<RwLock<ClientRegistry>>) -> Option<Outbound> {
+        let outbound = clients.read().unwrap().get(id).map(|c| c.outbound.clone());
+        if outbound.is_none() {
+            debug!("Client ({}) not available", id);
+        }
+        outbound
+    }
+
+    pub fn send_packet_to(id: i32, clients: &Arc<RwLock<ClientRegistry>>, packet: &Packet) {
+        if let Some(outbound) = Self::outbound_of(id, clients) {
+            outbound.send(packet);
+        }
+    }
+
+    /// Relays `message` from `id` to `target`, provided they play in the
+    /// same match.
+    pub fn send_message_to(
+        id: i32,
+        target: i32,
+        clients: &Arc<RwLock<ClientRegistry>>,
+        message: &[u8],
+    ) {
+        let clients = clients.read().unwrap();
+        let (Some(sender), Some(receiver)) = (clients.get(id), clients.get(target)) else {
+            debug!("Client ({}) not available", target);
+            return;
+        };
+        if sender.match_id == -1 || receiver.match_id != sender.match_id {
+            debug!(
+                "Client ({}) sent a message to {} outside its match, dropped",
+                id, target
+            );
+            return;
+        }
+        receiver.outbound.relay(message);
+        trace!("Sended Message to {}", target);
+    }
+
+    pub fn send_message(id: i32, clients: &Arc<RwLock<ClientRegistry>>, message: &[u8]) {
+        let clients = clients.read().unwrap();
+        let Some(match_id) = clients.get(id).map(|c| c.match_id) else {
+            return;
+        };
+
+        clients
+            .members(match_id)
+            .filter(|c| c.id != id)
+            .for_each(|c| c.outbound.relay(message));
+    }
+
+    /// Turns away a connection the server has no room for.
+    pub fn refuse(mut stream: TcpStream, peer_addr: SocketAddr) {
+        warn!("Client {} refused, the server is full", peer_addr);
+        let packet = Packet::Disconnect {
+            reason: DisconnectReason::ServerFull,
+            message: "Server is full, try again later".to_owned(),
+        };
+        let _ = stream.write_all(&packet.serialize_with_header());
+        let _ = stream.shutdown(std::net::Shutdown::Both);
+    }
+
+    /// Serves the client from its own reader and writer threads, used with
+    /// [`IoMode::Blocking`]. Fails when the socket cannot be shared between
+    /// them.
+    pub fn start(
+        mut self,
+        tx: Dispatcher,
+        clients: Arc<RwLock<ClientRegistry>>,
+        frame_limits: FrameLimits,
+        heartbeat: Heartbeat,
+        logins: Logins,
+        names: Arc<NameRules>,
+    ) -> io::Result<Self> {
+        let running = self.running.clone();
+        let mut stream = self.stream.try_clone()?;
+        let writer = stream.try_clone()?;
+        let mut session = Session::new(self.id, self.peer_addr, &self.outbound, logins, names);
+        // A peer that stops reading must not hold its writer forever
+        let _ = writer.set_write_timeout(Some(heartbeat.timeout));
+        self.writer = Some(self.outbound.spawn_writer(writer));
+        self.thread = Some(thread::spawn(move || {
+            // Pings keep healthy clients talking, so silence means the peer is gone
+            let _ = stream.set_read_timeout(Some(heartbeat.timeout));
+            while running.load(Ordering::Relaxed) {
+                let (packet, buffer) = match helpers::read_message(
+                    &mut stream,
+                    frame_limits.max_for(session.logged_in()),
+                ) {
+                    Ok(frame) => frame,
+                    Err(err) => {
+                        session.read_failed(err, &tx, &clients, heartbeat);
+                        break;
+                    }
+                };
+
+                if !session.handle_frame(packet, buffer, &tx, &clients) {
+                    break;
+                }
+                session.wait_for_login();
+            }
+        }));
+        Ok(self)
+    }
+
+    pub fn join(&mut self) {
+        self.running.store(false, Ordering::Relaxed);
+        self.outbound.close();
+        // Lets the writer flush what is queued before the socket goes away
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+        let _ = self.stream.shutdown(std::net::Shutdown::Both);
+        if let Some(handler) = self.thread.take() {
+            let _ = handler.join();
+        }
+    }
+}
+
+pub fn send_error(
+    id: i32,
+    request_id: Option<u32>,
+    clients: &Arc<RwLock<ClientRegistry>>,
+    request: RequestKind,
+    code: ErrorCode,
+    message: String,
+) {
+    info!(
+        "Client ({}) {:?} failed: {:?} {}",
+        id, request, code, message
+    );
+    Client::send_packet_to(
+        id,
+        clients,
+        &Packet::Error {
+            request,
+            code,
+            message,
+        }
+        .reply_to(request_id),
+    );
+}
+
+/// Acknowledges a correlated request that has no other reply.
+pub fn send_ack(id: i32, request_id: Option<u32>, clients: &Arc<RwLock<ClientRegistry>>) {
+    if request_id.is_some() {
+        Client::send_packet_to(id, clients, &Packet::Ack.reply_to(request_id));
+    }
+}
+
+/// Pings every client past the handshake each `interval` until `stop` is
+/// dropped. Clients that stop answering run into the read timeout set by
+/// [`Client::start`].
+pub fn heartbeat(clients: Arc<RwLock<ClientRegistry>>, interval: Duration, stop: Receiver<()>) {
+    while let Err(RecvTimeoutError::Timeout) = stop.recv_timeout(interval) {
+        let now = Instant::now();
+        for client in clients.write().unwrap().iter_mut() {
+            if client.state == ClientState::Handshake {
+                continue;
+            }
+            if client.ping_sent.is_none() {
+                client.ping_sent = Some(now);
+            }
+            client.outbound.send(&Packet::Ping);
+        }
+    }
+}
+
+pub fn set_client_state(id: i32, state: ClientState, clients: &Arc<RwLock<ClientRegistry>>) {
+    clients.write().unwrap().set_state(id, state);
+}
+
+/// See [`ClientRegistry::set_match`].
+pub fn set_client_match(
+    id: i32,
+    match_id: i32,
+    state: ClientState,
+    clients: &Arc<RwLock<ClientRegistry>>,
+) {
+    clients.write().unwrap().set_match(id, match_id, state);
+}
+
+/// Moves `id` into match `match_id`, see [`ClientRegistry::enter_match`].
+/// Returns the relay token issued to the client. When the client is no
+/// longer in the menu, `request` is answered with [`ErrorCode::InvalidState`]
+/// and `None` is returned.
+pub fn enter_match(
+    id: i32,
+    match_id: i32,
+    state: ClientState,
+    request: RequestKind,
+    request_id: Option<u32>,
+    clients: &Arc<RwLock<ClientRegistry>>,
+) -> Option<u64> {
+    let entered = clients.write().unwrap().enter_match(id, match_id, state);
+    match entered {
+        Ok(token) => Some(token),
+        Err(current) => {
+            if let Some(current) = current {
+                send_error(
+                    id,
+                    request_id,
+                    clients,
+                    request,
+                    ErrorCode::InvalidState,
+                    format!("{:?} is not allowed while {:?}", request, current),
+                );
+            }
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::net::TcpListener;
+
+    use super::*;
+    use crate::outbound::Next;
+
+    /// A client record backed by a real loopback connection.
+    pub(crate) fn connected(id: i32) -> Client {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let peer_addr = stream.local_addr().unwrap();
+        Client::new(id, peer_addr, stream, OutboundConfig::default())
+    }
+
+    fn registry(members: &[(i32, i32)]) -> Arc<RwLock<ClientRegistry>> {
+        let mut clients = ClientRegistry::new();
+        for &(id, match_id) in members {
+            clients.insert(connected(id));
+            if match_id != -1 {
+                clients.set_match(id, match_id, ClientState::MatchClient);
+            }
+        }
+        Arc::new(RwLock::new(clients))
+    }
+
+    fn relayed(id: i32, clients: &Arc<RwLock<ClientRegistry>>) -> bool {
+        let outbound = Client::outbound_of(id, clients).unwrap();
+        matches!(outbound.try_pop(), Next::Frame(_))
+    }
+
+    #[test]
+    fn send_message_to_stays_within_the_match() {
+        let clients = registry(&[(1, 7), (2, 7), (3, 8), (4, -1)]);
+
+        Client::send_message_to(1, 2, &clients, b"hi");
+        assert!(relayed(2, &clients));
+
+        Client::send_message_to(1, 3, &clients, b"hi");
+        assert!(!relayed(3, &clients));
+        Client::send_message_to(1, 4, &clients, b"hi");
+        assert!(!relayed(4, &clients));
+        Client::send_message_to(4, 1, &clients, b"hi");
+        assert!(!relayed(1, &clients));
+        Client::send_message_to(1, 5, &clients, b"hi");
+    }
+}
@@ -0,0 +1,513 @@
+use std::{env, fmt, fs, str::FromStr, sync::Arc, time::Duration};
+
+use log::LevelFilter;
+
+use crate::{Anonymous, Authenticator, CredentialsFile, ServerConfig, SignedTokens};
+
+/// Prefix of the environment variables overriding settings, `MW_TCP_ADDR`
+/// overrides `tcp_addr` and so on.
+const ENV_PREFIX: &str = "MW_";
+
+/// Every setting, by its name in the configuration file. The command line
+/// flag is the same name with dashes, `--tcp-addr` for `tcp_addr`.
+const KEYS: &[(&str, &str)] = &[
+    ("tcp_addr", "address the lobby listens on"),
+    ("udp_addr", "address the gameplay relay listens on"),
+    ("io_mode", "event_loop or blocking"),
+    ("udp_workers", "threads relaying UDP datagrams"),
+    ("login_workers", "threads checking login credentials"),
+    ("udp_buffer_size", "largest UDP datagram accepted, in bytes"),
+    ("max_clients", "connections served at once"),
+    ("max_rooms", "matches running at once"),
+    (
+        "frame_limit_before_login",
+        "largest frame before login, in bytes",
+    ),
+    (
+        "frame_limit_after_login",
+        "largest frame after login, in bytes",
+    ),
+    (
+        "dispatcher_queue",
+        "lobby requests waiting for the dispatcher",
+    ),
+    ("udp_relay_queue", "datagrams waiting for a relay worker"),
+    ("room_queue", "requests waiting for a match's thread"),
+    ("login_queue", "logins waiting for a login worker"),
+    ("outbound_queue", "frames waiting to be written to a client"),
+    (
+        "outbound_overflow",
+        "drop_oldest, drop_low_priority or disconnect",
+    ),
+    ("heartbeat_interval_ms", "time between pings"),
+    (
+        "heartbeat_timeout_ms",
+        "silence after which a client is dropped",
+    ),
+    (
+        "shutdown_grace_ms",
+        "time running matches get to end on shutdown",
+    ),
+    ("max_players", "members a match takes at most, up to 1024"),
+    (
+        "host_migration",
+        "oldest_member, lowest_latency or delete_room",
+    ),
+    (
+        "late_join",
+        "let clients join started matches, true or false",
+    ),
+    (
+        "match_start_delay_ms",
+        "time members of a starting match get to load the map",
+    ),
+    ("region", "where the server runs, shown in the match list"),
+    ("match_list_page_size", "most entries in one match list"),
+    ("name_min_len", "shortest name, in characters"),
+    ("name_max_len", "longest name, in characters"),
+    (
+        "name_extra_chars",
+        "characters allowed in names besides letters and digits",
+    ),
+    ("name_reserved", "comma separated names nobody may take"),
+    ("unique_names", "number names already in use, true or false"),
+    (
+        "allow_rename",
+        "let logged in clients change name, true or false",
+    ),
+    ("auth", "anonymous, password or token"),
+    ("auth_file", "accounts file, with auth = password"),
+    ("auth_secret", "token signing secret, with auth = token"),
+    ("log_level", "off, error, warn, info, debug or trace"),
+];
+
+/// Why the settings could not be loaded.
+#[derive(Debug)]
+pub enum ConfigError {
+    /// The configuration file could not be read.
+    Read { path: String, error: std::io::Error },
+    /// The configuration file is not valid TOML.
+    Parse { path: String, message: String },
+    /// A setting that does not exist, `source` tells where it came from.
+    UnknownKey { source: String, key: String },
+    /// A command line flag without its value.
+    MissingValue(String),
+    /// A setting with a value it cannot take.
+    Invalid { key: String, message: String },
+    /// `--help` was given, the caller should print [`usage`] and exit.
+    Help,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Read { path, error } => write!(f, "cannot read {}: {}", path, error),
+            ConfigError::Parse { path, message } => write!(f, "invalid {}: {}", path, message),
+            ConfigError::UnknownKey { source, key } => {
+                write!(f, "unknown setting {} in {}", key, source)
+            }
+            ConfigError::MissingValue(flag) => write!(f, "missing value for {}", flag),
+            ConfigError::Invalid { key, message } => write!(f, "invalid {}: {}", key, message),
+            ConfigError::Help => write!(f, "help requested"),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl ConfigError {
+    pub(crate) fn invalid(key: &str, message: impl Into<String>) -> Self {
+        ConfigError::Invalid {
+            key: key.to_owned(),
+            message: message.into(),
+        }
+    }
+}
+
+/// How logins are checked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AuthMethod {
+    /// Anybody gets in under the name they ask for, see [`Anonymous`].
+    #[default]
+    Anonymous,
+    /// Accounts from a [`CredentialsFile`].
+    Password,
+    /// Tokens issued by the backend, see [`SignedTokens`].
+    Token,
+}
+
+impl FromStr for AuthMethod {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "anonymous" => Ok(AuthMethod::Anonymous),
+            "password" => Ok(AuthMethod::Password),
+            "token" => Ok(AuthMethod::Token),
+            _ => Err("expected anonymous, password or token".to_owned()),
+        }
+    }
+}
+
+/// Login settings, turned into an [`Authenticator`] at startup.
+#[derive(Clone, Default)]
+pub struct Auth {
+    pub method: AuthMethod,
+    pub credentials_file: Option<String>,
+    pub token_secret: Option<String>,
+}
+
+impl fmt::Debug for Auth {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Auth")
+            .field("method", &self.method)
+            .field("credentials_file", &self.credentials_file)
+            .field("token_secret", &self.token_secret.as_ref().map(|_| "..."))
+            .finish()
+    }
+}
+
+impl Auth {
+    /// Builds the authenticator, reading the credentials file if there is
+    /// one.
+    pub fn authenticator(&self) -> Result<Arc<dyn Authenticator>, ConfigError> {
+        match self.method {
+            AuthMethod::Anonymous => Ok(Arc::new(Anonymous)),
+            AuthMethod::Password => {
+                let path = self.credentials_file.as_deref().ok_or_else(|| {
+                    ConfigError::invalid("auth_file", "required by auth = password")
+                })?;
+                let file = CredentialsFile::load(path).map_err(|error| ConfigError::Read {
+                    path: path.to_owned(),
+                    error,
+                })?;
+                Ok(Arc::new(file))
+            }
+            AuthMethod::Token => {
+                let secret = self
+                    .token_secret
+                    .as_deref()
+                    .filter(|secret| !secret.is_empty())
+                    .ok_or_else(|| {
+                        ConfigError::invalid("auth_secret", "required by auth = token")
+                    })?;
+                Ok(Arc::new(SignedTokens::new(secret)))
+            }
+        }
+    }
+}
+
+/// Everything the server binary can be tuned with.
+#[derive(Debug, Clone)]
+pub struct Settings {
+    pub server: ServerConfig,
+    pub auth: Auth,
+    pub log_level: LevelFilter,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            server: ServerConfig::default(),
+            auth: Auth::default(),
+            log_level: LevelFilter::Info,
+        }
+    }
+}
+
+impl Settings {
+    /// Builds the settings from the defaults, then the configuration file
+    /// named by `--config` or `MW_CONFIG`, then `MW_*` environment variables,
+    /// then the command line flags in `args`, each overriding the previous
+    /// ones. The result is validated.
+    pub fn load(args: impl IntoIterator<Item = String>) -> Result<Self, ConfigError> {
+        let flags = parse_args(args)?;
+
+        let mut settings = Settings::default();
+
+        let path = flags
+            .iter()
+            .find(|(key, _)| key == "config")
+            .map(|(_, path)| path.clone())
+            .or_else(|| env::var(format!("{}CONFIG", ENV_PREFIX)).ok());
+        if let Some(path) = path {
+            settings.apply_file(&path)?;
+        }
+
+        for (key, _) in KEYS {
+            if let Ok(value) = env::var(env_name(key)) {
+                settings.set(key, &value)?;
+            }
+        }
+
+        for (key, value) in flags.iter().filter(|(key, _)| key != "config") {
+            if !KEYS.iter().any(|(known, _)| known == key) {
+                return Err(ConfigError::UnknownKey {
+                    source: "command line".to_owned(),
+                    key: flag_name(key),
+                });
+            }
+            settings.set(key, value)?;
+        }
+
+        settings.server.validate()?;
+        Ok(settings)
+    }
+
+    fn apply_file(&mut self, path: &str) -> Result<(), ConfigError> {
+        let text = fs::read_to_string(path).map_err(|error| ConfigError::Read {
+            path: path.to_owned(),
+            error,
+        })?;
+        let table = text
+            .parse::<toml::Table>()
+            .map_err(|err| ConfigError::Parse {
+                path: path.to_owned(),
+                message: err.to_string(),
+            })?;
+
+        for (key, value) in table {
+            if !KEYS.iter().any(|(known, _)| *known == key) {
+                return Err(ConfigError::UnknownKey {
+                    source: path.to_owned(),
+                    key,
+                });
+            }
+            let value = match value {
+                toml::Value::String(value) => value,
+                toml::Value::Integer(value) => value.to_string(),
+                toml::Value::Boolean(value) => value.to_string(),
+                other => {
+                    return Err(ConfigError::invalid(
+                        &key,
+                        format!(
+                            "expected a string, an integer or a boolean, got {}",
+                            other.type_str()
+                        ),
+                    ));
+                }
+            };
+            self.set(&key, &value)?;
+        }
+        Ok(())
+    }
+
+    fn set(&mut self, key: &str, value: &str) -> Result<(), ConfigError> {
+        let config = &mut self.server;
+        match key {
+            "tcp_addr" => config.tcp_addr = value.to_owned(),
+            "udp_addr" => config.udp_addr = value.to_owned(),
+            "io_mode" => config.io_mode = parse(key, value)?,
+            "udp_workers" => config.udp_workers = parse(key, value)?,
+            "login_workers" => config.login_workers = parse(key, value)?,
+            "udp_buffer_size" => config.udp_buffer_size = parse(key, value)?,
+            "max_clients" => config.max_clients = parse(key, value)?,
+            "max_rooms" => config.max_rooms = parse(key, value)?,
+            "frame_limit_before_login" => config.frame_limits.before_login = parse(key, value)?,
+            "frame_limit_after_login" => config.frame_limits.after_login = parse(key, value)?,
+            "dispatcher_queue" => config.queue_limits.dispatcher = parse(key, value)?,
+            "udp_relay_queue" => config.queue_limits.udp_relay = parse(key, value)?,
+            "room_queue" => config.queue_limits.room = parse(key, value)?,
+            "login_queue" => config.queue_limits.login = parse(key, value)?,
+            "outbound_queue" => config.outbound.capacity = parse(key, value)?,
+            "outbound_overflow" => config.outbound.overflow = parse(key, value)?,
+            "heartbeat_interval_ms" => {
+                config.heartbeat.interval = Duration::from_millis(parse(key, value)?)
+            }
+            "heartbeat_timeout_ms" => {
+                config.heartbeat.timeout = Duration::from_millis(parse(key, value)?)
+            }
+            "shutdown_grace_ms" => {
+                config.shutdown_grace = Duration::from_millis(parse(key, value)?)
+            }
+            "max_players" => config.max_players = parse(key, value)?,
+            "host_migration" => config.host_migration = parse(key, value)?,
+            "late_join" => config.late_join = parse(key, value)?,
+            "match_start_delay_ms" => {
+                config.match_start_delay = Duration::from_millis(parse(key, value)?)
+            }
+            "region" => config.region = value.to_owned(),
+            "match_list_page_size" => config.match_list_page_size = parse(key, value)?,
+            "name_min_len" => config.names.min_len = parse(key, value)?,
+            "name_max_len" => config.names.max_len = parse(key, value)?,
+            "name_extra_chars" => config.names.extra_chars = value.to_owned(),
+            "name_reserved" => {
+                config.names.reserved = value
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|name| !name.is_empty())
+                    .map(str::to_owned)
+                    .collect()
+            }
+            "unique_names" => config.names.unique = parse(key, value)?,
+            "allow_rename" => config.names.allow_rename = parse(key, value)?,
+            "auth" => self.auth.method = parse(key, value)?,
+            "auth_file" => self.auth.credentials_file = Some(value.to_owned()),
+            "auth_secret" => self.auth.token_secret = Some(value.to_owned()),
+            "log_level" => self.log_level = parse(key, value)?,
+            _ => unreachable!("unknown setting {}", key),
+        }
+        Ok(())
+    }
+}
+
+/// Command line help, listing every flag.
+pub fn usage() -> String {
+    let mut usage = String::from(
+        "Usage: network_manager [--config <file>] [--<setting> <value>]...\n\n\
+         Settings are read from the file, then from MW_<SETTING> environment\n\
+         variables, then from the flags.\n\n",
+    );
+    usage.push_str(&format!(
+        "  {:<28} {}\n",
+        "--config", "TOML configuration file"
+    ));
+    for (key, help) in KEYS {
+        usage.push_str(&format!("  {:<28} {}\n", flag_name(key), help));
+    }
+    usage
+}
+
+/// Splits `--key value` and `--key=value` flags into setting names and values.
+fn parse_args(
+    args: impl IntoIterator<Item = String>,
+) -> Result<Vec<(String, String)>, ConfigError> {
+    let mut flags = Vec::new();
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        if arg == "-h" || arg == "--help" {
+            return Err(ConfigError::Help);
+        }
+        let Some(flag) = arg.strip_prefix("--") else {
+            return Err(ConfigError::UnknownKey {
+                source: "command line".to_owned(),
+                key: arg,
+            });
+        };
+        let (name, value) = match flag.split_once('=') {
+            Some((name, value)) => (name.to_owned(), value.to_owned()),
+            None => {
+                let value = args
+                    .next()
+                    .ok_or_else(|| ConfigError::MissingValue(arg.clone()))?;
+                (flag.to_owned(), value)
+            }
+        };
+        flags.push((name.replace('-', "_"), value));
+    }
+    Ok(flags)
+}
+
+fn parse<T: FromStr>(key: &str, value: &str) -> Result<T, ConfigError>
+where
+    T::Err: fmt::Display,
+{
+    value
+        .parse()
+        .map_err(|err: T::Err| ConfigError::invalid(key, format!("{:?}: {}", value, err)))
+}
+
+fn flag_name(key: &str) -> String {
+    format!("--{}", key.replace('_', "-"))
+}
+
+fn env_name(key: &str) -> String {
+    format!("{}{}", ENV_PREFIX, key.to_uppercase())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        path::PathBuf,
+        process,
+        sync::{Mutex, MutexGuard},
+    };
+
+    use super::*;
+
+    /// Held by every test loading settings, as [`Settings::load`] reads the
+    /// environment that one of them changes.
+    static ENV: Mutex<()> = Mutex::new(());
+
+    fn lock_env() -> MutexGuard<'static, ()> {
+        ENV.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Writes `text` to a configuration file only this test uses.
+    fn config_file(test: &str, text: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("network_manager-{}-{}.toml", process::id(), test));
+        fs::write(&path, text).unwrap();
+        path
+    }
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn load_prefers_flags_then_environment_then_file() {
+        let path = config_file(
+            "precedence",
+            "region = \"file\"\nmax_rooms = 10\nmax_players = 10\nlate_join = true\n",
+        );
+        let _env = lock_env();
+        // SAFETY: the environment is only read by tests holding `ENV`
+        unsafe {
+            env::set_var("MW_MAX_ROOMS", "20");
+            env::set_var("MW_MAX_PLAYERS", "20");
+        }
+        let settings = Settings::load(args(&[
+            "--config",
+            path.to_str().unwrap(),
+            "--max-players",
+            "30",
+        ]));
+        // SAFETY: as above
+        unsafe {
+            env::remove_var("MW_MAX_ROOMS");
+            env::remove_var("MW_MAX_PLAYERS");
+        }
+        fs::remove_file(&path).unwrap();
+
+        let settings = settings.unwrap();
+        assert_eq!(settings.server.region, "file");
+        assert!(settings.server.late_join);
+        assert_eq!(settings.server.max_rooms, 20);
+        assert_eq!(settings.server.max_players, 30);
+        assert_eq!(
+            settings.server.tcp_addr,
+            ServerConfig::default().tcp_addr,
+            "settings given nowhere keep their default"
+        );
+    }
+
+    #[test]
+    fn load_refuses_unknown_settings() {
+        let _env = lock_env();
+        let path = config_file("unknown", "max_romos = 10\n");
+        let from_file = Settings::load(args(&["--config", path.to_str().unwrap()]));
+        fs::remove_file(&path).unwrap();
+        assert!(matches!(
+            from_file,
+            Err(ConfigError::UnknownKey { key, .. }) if key == "max_romos"
+        ));
+
+        assert!(matches!(
+            Settings::load(args(&["--max-romos", "10"])),
+            Err(ConfigError::UnknownKey { key, .. }) if key == "--max-romos"
+        ));
+    }
+
+    #[test]
+    fn load_refuses_invalid_values() {
+        let _env = lock_env();
+        assert!(matches!(
+            Settings::load(args(&["--host-migration", "whoever"])),
+            Err(ConfigError::Invalid { key, .. }) if key == "host_migration"
+        ));
+        assert!(matches!(
+            Settings::load(args(&["--max-players"])),
+            Err(ConfigError::MissingValue(flag)) if flag == "--max-players"
+        ));
+    }
+}
@@ -0,0 +1,791 @@
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, Instant},
+};
+
+use crossbeam::channel::{Receiver, Sender, TrySendError, bounded};
+use log::{debug, info, warn};
+use network_types::connection::{
+    DisconnectReason, ErrorCode, MatchInfo, MatchOptions, MatchQuery, Packet, RequestKind,
+    Visibility,
+};
+
+use crate::{
+    Hooks,
+    client::{Client, enter_match, send_ack, send_error},
+    listing::{self, ListingSettings, Subscribers},
+    metrics::Metrics,
+    registry::{ClientRegistry, CodeFailures, MatchRegistry, normalize_code},
+    room::{Match, RoomCommand, RoomHandle, RoomSettings},
+    session::ClientState,
+};
+
+#[derive(serde::Serialize, Debug, Clone)]
+pub(crate) enum Message {
+    RemoveFromListMatches {
+        id: i32,
+        request_id: Option<u32>,
+    },
+    ListMatches {
+        id: i32,
+        query: MatchQuery,
+        request_id: Option<u32>,
+    },
+    JoinMatch {
+        id: i32,
+        room_id: i32,
+        password: Option<String>,
+        request_id: Option<u32>,
+    },
+    NewMatch {
+        id: i32,
+        room_name: String,
+        options: MatchOptions,
+        request_id: Option<u32>,
+    },
+    JoinByCode {
+        id: i32,
+        code: String,
+        password: Option<String>,
+        request_id: Option<u32>,
+    },
+    RegenerateJoinCode {
+        id: i32,
+        room_id: i32,
+        request_id: Option<u32>,
+    },
+    DeleteMatch {
+        id: i32,
+        room_id: i32,
+        request_id: Option<u32>,
+    },
+    LeaveMatch {
+        id: i32,
+        room_id: i32,
+        request_id: Option<u32>,
+    },
+    StartMatch {
+        id: i32,
+        room_id: i32,
+        map: String,
+        request_id: Option<u32>,
+    },
+    SpawnPlayers {
+        id: i32,
+        room_id: i32,
+        positions: Vec<(f32, f32, f32)>,
+        request_id: Option<u32>,
+    },
+    Disconnected {
+        id: i32,
+    },
+    /// A member of match `match_id` logged in again under another name.
+    Renamed {
+        id: i32,
+        match_id: i32,
+        previous: String,
+    },
+    /// Sent by a match whenever its member count changes, which is also
+    /// when its owner can change. `joined` is the member that just joined,
+    /// if any.
+    RoomChanged {
+        room_id: i32,
+        players: i32,
+        owner_id: i32,
+        joined: Option<i32>,
+    },
+    /// Sent by a match once its host started it.
+    RoomStarted {
+        room_id: i32,
+        map: String,
+    },
+    /// Sent by a match once it is over, its handle can be dropped.
+    RoomClosed {
+        room_id: i32,
+    },
+    /// Turns drain mode on or off. While draining, new matches are refused
+    /// and [`Hooks`] hear about it once the last match is over.
+    SetDraining {
+        draining: bool,
+    },
+    /// Refuses new matches and waits up to `grace` for the running ones to
+    /// end, then hangs up on every client and stops the dispatcher.
+    Shutdown {
+        grace: Duration,
+    },
+}
+
+impl Message {
+    /// The client, request id and request kind to answer when this is a
+    /// lobby request.
+    fn request(&self) -> Option<(i32, Option<u32>, RequestKind)> {
+        match *self {
+            Message::RemoveFromListMatches { id, request_id }
+            | Message::ListMatches { id, request_id, .. } => {
+                Some((id, request_id, RequestKind::ListMatches))
+            }
+            Message::JoinMatch { id, request_id, .. }
+            | Message::JoinByCode { id, request_id, .. } => {
+                Some((id, request_id, RequestKind::JoinMatch))
+            }
+            Message::RegenerateJoinCode { id, request_id, .. } => {
+                Some((id, request_id, RequestKind::RegenerateJoinCode))
+            }
+            Message::NewMatch { id, request_id, .. } => {
+                Some((id, request_id, RequestKind::NewMatch))
+            }
+            Message::DeleteMatch { id, request_id, .. } => {
+                Some((id, request_id, RequestKind::DeleteMatch))
+            }
+            Message::LeaveMatch { id, request_id, .. } => {
+                Some((id, request_id, RequestKind::LeaveMatch))
+            }
+            Message::StartMatch { id, request_id, .. } => {
+                Some((id, request_id, RequestKind::StartMatch))
+            }
+            Message::SpawnPlayers { id, request_id, .. } => {
+                Some((id, request_id, RequestKind::SpawnPlayers))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Sending side of the dispatcher's bounded queue.
+#[derive(Debug, Clone)]
+pub(crate) struct Dispatcher {
+    tx: Sender<Message>,
+    metrics: Arc<Metrics>,
+    /// Notices that found the queue full, see [`Dispatcher::notify`].
+    parked: Arc<Mutex<Vec<Message>>>,
+}
+
+impl Dispatcher {
+    pub fn new(capacity: usize, metrics: Arc<Metrics>) -> (Self, Receiver<Message>) {
+        let (tx, rx) = bounded(capacity);
+        metrics.dispatcher.set_capacity(capacity);
+        let parked = Arc::new(Mutex::new(Vec::new()));
+        (
+            Self {
+                tx,
+                metrics,
+                parked,
+            },
+            rx,
+        )
+    }
+
+    /// Tells the dispatcher that client `id` is gone, see
+    /// [`Dispatcher::notify`].
+    pub fn disconnected(&self, id: i32) {
+        self.notify(Message::Disconnected { id });
+    }
+
+    /// Queues a notice that must not be lost without ever waiting, so that
+    /// the event loop never stalls on a saturated dispatcher. When the queue
+    /// is full the notice is parked instead, and the dispatcher picks it up
+    /// after the next message it takes out of the queue. Notices must do no
+    /// harm when handled twice.
+    pub fn notify(&self, message: Message) {
+        match self.tx.try_send(message) {
+            Ok(()) => self.metrics.dispatcher.observe(self.tx.len()),
+            Err(TrySendError::Full(message)) => {
+                self.metrics.dispatcher.overflow();
+                self.parked.lock().unwrap().push(message.clone());
+                // The queue may have emptied in between, with nothing left to
+                // wake the dispatcher
+                let _ = self.tx.try_send(message);
+            }
+            Err(TrySendError::Disconnected(_)) => {}
+        }
+    }
+
+    fn take_parked(&self) -> Vec<Message> {
+        std::mem::take(&mut *self.parked.lock().unwrap())
+    }
+
+    /// Queues a message that must not be lost, waiting for room if needed.
+    /// Messages sent after the dispatcher stopped are dropped.
+    pub fn send(&self, message: Message) {
+        if self.tx.send(message).is_ok() {
+            self.metrics.dispatcher.observe(self.tx.len());
+        }
+    }
+
+    /// Queues a lobby request, answering it with [`ErrorCode::ServerBusy`]
+    /// when the dispatcher is saturated.
+    pub fn request(&self, message: Message, clients: &Arc<RwLock<ClientRegistry>>) {
+        match self.tx.try_send(message) {
+            Ok(()) => self.metrics.dispatcher.observe(self.tx.len()),
+            Err(TrySendError::Full(message)) => {
+                self.metrics.dispatcher.overflow();
+                if let Some((id, request_id, request)) = message.request() {
+                    send_error(
+                        id,
+                        request_id,
+                        clients,
+                        request,
+                        ErrorCode::ServerBusy,
+                        "Server is busy, try again later".to_owned(),
+                    );
+                }
+            }
+            // The server is shutting down, nobody is left to answer
+            Err(TrySendError::Disconnected(_)) => {}
+        }
+    }
+}
+
+/// Hands `command` to match `room_id`.
+fn route(
+    matches: &MatchRegistry,
+    room_id: i32,
+    command: RoomCommand,
+    clients: &Arc<RwLock<ClientRegistry>>,
+) {
+    match matches.get(room_id) {
+        Some(room) => room.send(command, clients),
+        None => command.reject(room_id, clients),
+    }
+}
+
+/// Applies `change` to match `room_id` and tells the subscribers what it did
+/// to the match list.
+fn update_room(
+    matches: &mut MatchRegistry,
+    room_id: i32,
+    subscribers: &Subscribers,
+    clients: &Arc<RwLock<ClientRegistry>>,
+    listing: &ListingSettings,
+    change: impl FnOnce(&mut RoomHandle),
+) {
+    let Some(room) = matches.get_mut(room_id) else {
+        return;
+    };
+    let clients = clients.read().unwrap();
+    let before = listing::listed(room, &clients, listing);
+    change(room);
+    let after = listing::listed(room, &clients, listing);
+    subscribers.publish(before.as_ref(), after.as_ref(), &clients);
+}
+
+/// The dispatcher: owns the match list and routes lobby requests to the
+/// matches, until told to shut down.
+pub fn dispatch(
+    rx: Receiver<Message>,
+    clients: Arc<RwLock<ClientRegistry>>,
+    tx: Dispatcher,
+    rooms: RoomSettings,
+    listing: ListingSettings,
+    max_rooms: usize,
+    hooks: Hooks,
+) {
+    let mut matches = MatchRegistry::new();
+    let mut code_failures = CodeFailures::new();
+
+    let mut subscribers = Subscribers::new();
+    // Set once shutting down, when the remaining matches are given up on
+    let mut deadline: Option<Instant> = None;
+    // While draining no matches are created or joined, and the embedder is
+    // told once the last one ended
+    let mut draining = false;
+    let mut drained = false;
+    let mut parked = VecDeque::new();
+    // Only routes, everything about a match happens on its own thread
+    loop {
+        if parked.is_empty() {
+            parked.extend(tx.take_parked());
+        }
+        let message = match (parked.pop_front(), deadline) {
+            (Some(message), _) => Some(message),
+            (None, None) => rx.recv().ok(),
+            (None, Some(_)) if matches.is_empty() => None,
+            (None, Some(deadline)) => rx.recv_deadline(deadline).ok(),
+        };
+        let Some(message) = message else {
+            break;
+        };
+        let refusal = if deadline.is_some() {
+            Some((ErrorCode::ShuttingDown, "Server is shutting down"))
+        } else if draining {
+            Some((ErrorCode::Draining, "Server is going down for maintenance"))
+        } else {
+            None
+        };
+        match message {
+            Message::RemoveFromListMatches { id, request_id } => {
+                subscribers.unsubscribe(id);
+                send_ack(id, request_id, &clients);
+            }
+            Message::ListMatches {
+                id,
+                query,
+                request_id,
+            } => {
+                let packet = listing::snapshot(
+                    &matches,
+                    &query,
+                    draining,
+                    &clients.read().unwrap(),
+                    &listing,
+                );
+                subscribers.subscribe(id, query);
+
+                Client::send_packet_to(id, &clients, &packet.reply_to(request_id));
+            }
+            Message::NewMatch {
+                id,
+                room_name,
+                mut options,
+                request_id,
+            } => {
+                let Some(owner_outbound) = clients
+                    .read()
+                    .unwrap()
+                    .get(id)
+                    .map(|owner| owner.outbound.clone())
+                else {
+                    continue;
+                };
+                if let Some((code, message)) = refusal {
+                    send_error(
+                        id,
+                        request_id,
+                        &clients,
+                        RequestKind::NewMatch,
+                        code,
+                        message.to_owned(),
+                    );
+                    continue;
+                }
+                if matches.len() >= max_rooms {
+                    send_error(
+                        id,
+                        request_id,
+                        &clients,
+                        RequestKind::NewMatch,
+                        ErrorCode::RoomLimitReached,
+                        "Too many matches are running, try again later".to_owned(),
+                    );
+                    continue;
+                }
+                match options.max_players {
+                    None => options.max_players = Some(rooms.max_players),
+                    Some(max_players) if (1..=rooms.max_players).contains(&max_players) => {}
+                    Some(max_players) => {
+                        send_error(
+                            id,
+                            request_id,
+                            &clients,
+                            RequestKind::NewMatch,
+                            ErrorCode::InvalidRequest,
+                            format!(
+                                "A match takes 1 to {} players, not {}",
+                                rooms.max_players, max_players
+                            ),
+                        );
+                        continue;
+                    }
+                }
+                // An empty password protects nothing
+                options.password = options.password.filter(|password| !password.is_empty());
+                options.tags = match listing::normalize_tags(options.tags) {
+                    Ok(tags) => tags,
+                    Err(message) => {
+                        send_error(
+                            id,
+                            request_id,
+                            &clients,
+                            RequestKind::NewMatch,
+                            ErrorCode::InvalidRequest,
+                            message,
+                        );
+                        continue;
+                    }
+                };
+                let match_id = matches.next_id();
+                let Some(relay_token) = enter_match(
+                    id,
+                    match_id,
+                    ClientState::MatchHost,
+                    RequestKind::NewMatch,
+                    request_id,
+                    &clients,
+                ) else {
+                    continue;
+                };
+                // Like joining, creating a match ends the subscription
+                subscribers.unsubscribe(id);
+                let join_code = matches.unused_code();
+
+                // Notify owner that the Match was created
+                owner_outbound.send(
+                    &Packet::MatchCreated {
+                        id: match_id,
+                        owner_id: id,
+                        room_name: room_name.clone(),
+                        join_code: join_code.clone(),
+                    }
+                    .reply_to(request_id),
+                );
+                owner_outbound.send(&Packet::RelayToken {
+                    room_id: match_id,
+                    token: relay_token,
+                });
+
+                matches.insert(
+                    Match::new(match_id, id, room_name, options, owner_outbound).spawn(
+                        join_code,
+                        clients.clone(),
+                        tx.clone(),
+                        rooms,
+                    ),
+                );
+
+                // Notify all clients on Match List about this new Match
+                let clients = clients.read().unwrap();
+                let added = matches
+                    .get(match_id)
+                    .and_then(|room| listing::listed(room, &clients, &listing));
+                subscribers.publish(None, added.as_ref(), &clients);
+            }
+            Message::JoinMatch {
+                id,
+                room_id,
+                password,
+                request_id,
+            } => match refusal {
+                Some((code, message)) => send_error(
+                    id,
+                    request_id,
+                    &clients,
+                    RequestKind::JoinMatch,
+                    code,
+                    message.to_owned(),
+                ),
+                None => {
+                    let command = RoomCommand::Join {
+                        id,
+                        password,
+                        request_id,
+                    };
+                    // Private matches are not to be found by id
+                    match matches.get(room_id) {
+                        Some(room) if room.visibility != Visibility::Private => {
+                            room.send(command, &clients)
+                        }
+                        _ => command.reject(room_id, &clients),
+                    }
+                }
+            },
+            Message::JoinByCode {
+                id,
+                code,
+                password,
+                request_id,
+            } => {
+                if let Some((code, message)) = refusal {
+                    send_error(
+                        id,
+                        request_id,
+                        &clients,
+                        RequestKind::JoinMatch,
+                        code,
+                        message.to_owned(),
+                    );
+                    continue;
+                }
+                let Some(ip) = clients.read().unwrap().get(id).map(|c| c.peer_addr.ip()) else {
+                    continue;
+                };
+                if code_failures.exhausted(ip, Instant::now()) {
+                    send_error(
+                        id,
+                        request_id,
+                        &clients,
+                        RequestKind::JoinMatch,
+                        ErrorCode::TooManyAttempts,
+                        "Too many wrong invite codes".to_owned(),
+                    );
+                    continue;
+                }
+                match matches.by_code(&normalize_code(&code)) {
+                    Some(room) => room.send(
+                        RoomCommand::Join {
+                            id,
+                            password,
+                            request_id,
+                        },
+                        &clients,
+                    ),
+                    None => {
+                        code_failures.record(ip, Instant::now());
+                        send_error(
+                            id,
+                            request_id,
+                            &clients,
+                            RequestKind::JoinMatch,
+                            ErrorCode::RoomNotFound,
+                            format!("No match has the code {:?}", code),
+                        );
+                    }
+                }
+            }
+            Message::RegenerateJoinCode {
+                id,
+                room_id,
+                request_id,
+            } => {
+                let Some(room) = matches.get(room_id) else {
+                    send_error(
+                        id,
+                        request_id,
+                        &clients,
+                        RequestKind::RegenerateJoinCode,
+                        ErrorCode::RoomNotFound,
+                        format!("Match {} does not exist", room_id),
+                    );
+                    continue;
+                };
+                if room.owner_id != id {
+                    send_error(
+                        id,
+                        request_id,
+                        &clients,
+                        RequestKind::RegenerateJoinCode,
+                        ErrorCode::NotHost,
+                        format!("Only the owner can change the code of match {}", room_id),
+                    );
+                    continue;
+                }
+                let Some(code) = matches.regenerate_code(room_id) else {
+                    continue;
+                };
+                info!("Match {} has a new join code", room_id);
+                let clients = clients.read().unwrap();
+                for member in clients.members(room_id) {
+                    let reply_to = if member.id == id { request_id } else { None };
+                    member.outbound.send(
+                        &Packet::JoinCode {
+                            room_id,
+                            code: code.clone(),
+                        }
+                        .reply_to(reply_to),
+                    );
+                }
+            }
+            Message::DeleteMatch {
+                id,
+                room_id,
+                request_id,
+            } => route(
+                &matches,
+                room_id,
+                RoomCommand::Delete { id, request_id },
+                &clients,
+            ),
+            Message::LeaveMatch {
+                id,
+                room_id,
+                request_id,
+            } => route(
+                &matches,
+                room_id,
+                RoomCommand::Leave { id, request_id },
+                &clients,
+            ),
+            Message::StartMatch {
+                id,
+                room_id,
+                map,
+                request_id,
+            } => route(
+                &matches,
+                room_id,
+                RoomCommand::Start {
+                    id,
+                    map,
+                    request_id,
+                },
+                &clients,
+            ),
+            Message::SpawnPlayers {
+                id,
+                room_id,
+                positions,
+                request_id,
+            } => {
+                debug!("SpawnPlayers");
+                if positions.is_empty() {
+                    warn!("Invalid spaws for {}", room_id);
+                    send_error(
+                        id,
+                        request_id,
+                        &clients,
+                        RequestKind::SpawnPlayers,
+                        ErrorCode::InvalidRequest,
+                        "No spawn positions given".to_owned(),
+                    );
+                    continue;
+                }
+                route(
+                    &matches,
+                    room_id,
+                    RoomCommand::SpawnPlayers {
+                        id,
+                        positions,
+                        request_id,
+                    },
+                    &clients,
+                );
+            }
+            Message::Disconnected { id } => {
+                subscribers.unsubscribe(id);
+
+                let Some(client) = clients.write().unwrap().remove(id) else {
+                    continue;
+                };
+                // Lets the writer flush what is queued and hang up
+                client.outbound.close();
+                info!("Client ({}) removed", id);
+                hooks.disconnected(id);
+
+                if client.match_id != -1
+                    && let Some(room) = matches.get(client.match_id)
+                {
+                    room.send(
+                        RoomCommand::Disconnected {
+                            id,
+                            name: client.name.clone(),
+                        },
+                        &clients,
+                    );
+                }
+            }
+            Message::Renamed {
+                id,
+                match_id,
+                previous,
+            } => {
+                // The match list shows the host by name
+                let Some(room) = matches.get(match_id).filter(|room| room.owner_id == id) else {
+                    continue;
+                };
+                let clients = clients.read().unwrap();
+                let after = listing::listed(room, &clients, &listing);
+                let before = after.clone().map(|info| MatchInfo {
+                    host: previous,
+                    ..info
+                });
+                subscribers.publish(before.as_ref(), after.as_ref(), &clients);
+            }
+            Message::RoomChanged {
+                room_id,
+                players,
+                owner_id,
+                joined,
+            } => {
+                if let Some(joined) = joined {
+                    subscribers.unsubscribe(joined);
+                }
+                // Only the dispatcher knows the invite code, hand it to a
+                // member that just took over as host
+                if let Some(room) = matches.get(room_id).filter(|r| r.owner_id != owner_id)
+                    && let Some(outbound) = Client::outbound_of(owner_id, &clients)
+                {
+                    outbound.send(&Packet::JoinCode {
+                        room_id,
+                        code: room.code.clone(),
+                    });
+                }
+                update_room(
+                    &mut matches,
+                    room_id,
+                    &subscribers,
+                    &clients,
+                    &listing,
+                    |room| {
+                        room.players = players;
+                        room.owner_id = owner_id;
+                    },
+                );
+            }
+            Message::RoomStarted { room_id, map } => update_room(
+                &mut matches,
+                room_id,
+                &subscribers,
+                &clients,
+                &listing,
+                |room| {
+                    room.started = true;
+                    room.map = Some(map);
+                },
+            ),
+            Message::RoomClosed { room_id } => {
+                if let Some(room) = matches.remove(room_id) {
+                    let clients = clients.read().unwrap();
+                    let removed = listing::listed(&room, &clients, &listing);
+                    subscribers.publish(removed.as_ref(), None, &clients);
+                    drop(clients);
+                    room.join();
+                }
+            }
+            Message::Shutdown { grace } => {
+                info!(
+                    "Shutting down, waiting up to {:?} for {} matches",
+                    grace,
+                    matches.len()
+                );
+                deadline = Some(Instant::now() + grace);
+            }
+            Message::SetDraining { draining: enabled } => {
+                if draining != enabled {
+                    info!(
+                        "Draining {}, {} matches running",
+                        if enabled { "started" } else { "stopped" },
+                        matches.len()
+                    );
+                }
+                draining = enabled;
+                drained = false;
+                // The flag is part of the snapshot, not of any one match
+                subscribers.resend(&matches, draining, &clients.read().unwrap(), &listing);
+            }
+        }
+
+        // Only match members are given the grace period, everybody else has
+        // nothing to wait for
+        if deadline.is_some() {
+            for client in clients.read().unwrap().iter() {
+                if client.match_id == -1 {
+                    client.outbound.close_with(&shutdown_notice());
+                }
+            }
+        }
+
+        if draining && !drained && matches.is_empty() {
+            info!("Drained, the last match is over");
+            drained = true;
+            hooks.drained();
+        }
+    }
+
+    for client in clients.read().unwrap().iter() {
+        client.outbound.close_with(&shutdown_notice());
+        hooks.disconnected(client.id);
+    }
+
+    // Matches may be waiting for room in the queue
+    drop(rx);
+    for room in matches.drain() {
+        room.join();
+    }
+}
+
+fn shutdown_notice() -> Packet {
+    Packet::Disconnect {
+        reason: DisconnectReason::ServerShutdown,
+        message: "Server is shutting down".to_owned(),
+    }
+}
@@ -0,0 +1,434 @@
+use std::{
+    collections::{HashMap, HashSet},
+    io::{self, ErrorKind, Read, Write},
+    net::Shutdown,
+    sync::{
+        Arc, Mutex, RwLock,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use log::{info, warn};
+use mio::{
+    Events, Interest, Poll, Token, Waker,
+    net::{TcpListener, TcpStream},
+};
+
+use crate::{
+    Hooks, NameRules, ServerConfig,
+    client::{Client, Heartbeat},
+    dispatcher::Dispatcher,
+    helpers::{self, ReadError},
+    login::Logins,
+    outbound::{Next, OutboundConfig},
+    registry::ClientRegistry,
+    session::{FrameLimits, Session},
+};
+
+const LISTENER: Token = Token(usize::MAX);
+const WAKER: Token = Token(usize::MAX - 1);
+
+/// How often silent connections are looked for.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long connections get to flush their last frames once the loop is
+/// told to stop.
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Tells the event loop that a connection has frames to write.
+#[derive(Debug, Clone)]
+pub struct Notifier {
+    token: Token,
+    waker: Arc<Waker>,
+    pending: Arc<Mutex<HashSet<Token>>>,
+}
+
+impl Notifier {
+    pub fn notify(&self) {
+        self.pending.lock().unwrap().insert(self.token);
+        let _ = self.waker.wake();
+    }
+}
+
+struct Connection {
+    stream: TcpStream,
+    session: Session,
+    read_buffer: Vec<u8>,
+    /// Unwritten part of the frame being sent.
+    write_buffer: Vec<u8>,
+    last_read: Instant,
+    /// Cleared once the main loop knows the client is gone. The connection
+    /// stays around until its outbound queue is flushed.
+    reading: bool,
+    /// Set while frames are left unread until a pending login is done.
+    paused: bool,
+}
+
+impl Connection {
+    fn read(
+        &mut self,
+        tx: &Dispatcher,
+        clients: &Arc<RwLock<ClientRegistry>>,
+        frame_limits: FrameLimits,
+        heartbeat: Heartbeat,
+    ) {
+        let mut chunk = [0u8; 4096];
+        loop {
+            // Frames received earlier go first
+            self.handle_frames(tx, clients, frame_limits, heartbeat);
+            if !self.reading {
+                return;
+            }
+            // Readiness is only reported once, so reading resumes from
+            // `resume` rather than on the next event
+            if self.session.login_pending() {
+                self.paused = true;
+                return;
+            }
+
+            let len = match self.stream.read(&mut chunk) {
+                Ok(0) => {
+                    self.read_failed(
+                        ReadError::Io(ErrorKind::UnexpectedEof.into()),
+                        tx,
+                        clients,
+                        heartbeat,
+                    );
+                    return;
+                }
+                Ok(len) => len,
+                Err(err) if err.kind() == ErrorKind::WouldBlock => return,
+                Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                Err(err) => {
+                    self.read_failed(err.into(), tx, clients, heartbeat);
+                    return;
+                }
+            };
+            self.last_read = Instant::now();
+            self.read_buffer.extend_from_slice(&chunk[..len]);
+        }
+    }
+
+    fn handle_frames(
+        &mut self,
+        tx: &Dispatcher,
+        clients: &Arc<RwLock<ClientRegistry>>,
+        frame_limits: FrameLimits,
+        heartbeat: Heartbeat,
+    ) {
+        while self.reading && !self.session.login_pending() {
+            let max_size = frame_limits.max_for(self.session.logged_in());
+            match helpers::take_message(&mut self.read_buffer, max_size) {
+                Ok(Some((packet, buffer))) => {
+                    self.reading = self.session.handle_frame(packet, buffer, tx, clients);
+                }
+                Ok(None) => break,
+                Err(err) => self.read_failed(ReadError::Decode(err), tx, clients, heartbeat),
+            }
+        }
+    }
+
+    /// Goes on reading once the login that paused the connection is done.
+    fn resume(
+        &mut self,
+        tx: &Dispatcher,
+        clients: &Arc<RwLock<ClientRegistry>>,
+        frame_limits: FrameLimits,
+        heartbeat: Heartbeat,
+    ) {
+        if self.paused && !self.session.login_pending() {
+            self.paused = false;
+            self.read(tx, clients, frame_limits, heartbeat);
+        }
+    }
+
+    fn read_failed(
+        &mut self,
+        err: ReadError,
+        tx: &Dispatcher,
+        clients: &Arc<RwLock<ClientRegistry>>,
+        heartbeat: Heartbeat,
+    ) {
+        self.session.read_failed(err, tx, clients, heartbeat);
+        self.reading = false;
+    }
+
+    /// Writes queued frames until the socket would block. Returns false once
+    /// the connection is finished with.
+    fn flush(&mut self) -> bool {
+        loop {
+            if self.write_buffer.is_empty() {
+                match self.session.outbound.try_pop() {
+                    Next::Frame(frame) => self.write_buffer = frame,
+                    Next::Empty => return true,
+                    Next::Closed => {
+                        let _ = self.stream.shutdown(Shutdown::Both);
+                        return false;
+                    }
+                }
+            }
+
+            match self.stream.write(&self.write_buffer) {
+                Ok(0) => return false,
+                Ok(len) => {
+                    self.write_buffer.drain(..len);
+                }
+                Err(err) if err.kind() == ErrorKind::WouldBlock => return true,
+                Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                Err(err) => {
+                    info!(
+                        "Client {:?} not available: {:?}",
+                        self.session.peer_addr, err
+                    );
+                    self.session.outbound.close();
+                    return false;
+                }
+            }
+        }
+    }
+}
+
+/// Serves every client connection from a single thread, used with
+/// [`IoMode::EventLoop`](crate::IoMode::EventLoop). Lobby requests are handed
+/// to the main loop through `tx`, exactly like the blocking reader threads
+/// do.
+pub struct EventLoop {
+    poll: Poll,
+    waker: Arc<Waker>,
+    listener: std::net::TcpListener,
+    registered: TcpListener,
+    tx: Dispatcher,
+    clients: Arc<RwLock<ClientRegistry>>,
+    frame_limits: FrameLimits,
+    heartbeat: Heartbeat,
+    outbound_config: OutboundConfig,
+    max_clients: usize,
+    logins: Logins,
+    names: Arc<NameRules>,
+    hooks: Hooks,
+}
+
+impl EventLoop {
+    pub fn new(
+        listener: std::net::TcpListener,
+        tx: Dispatcher,
+        clients: Arc<RwLock<ClientRegistry>>,
+        config: &ServerConfig,
+        logins: Logins,
+        hooks: Hooks,
+    ) -> io::Result<Self> {
+        let poll = Poll::new()?;
+        let waker = Arc::new(Waker::new(poll.registry(), WAKER)?);
+
+        // Accepting goes through the std listener, the mio one only reports
+        // readiness
+        listener.set_nonblocking(true)?;
+        let mut registered = TcpListener::from_std(listener.try_clone()?);
+        poll.registry()
+            .register(&mut registered, LISTENER, Interest::READABLE)?;
+
+        Ok(Self {
+            poll,
+            waker,
+            listener,
+            registered,
+            tx,
+            clients,
+            frame_limits: config.frame_limits,
+            heartbeat: config.heartbeat,
+            outbound_config: config.outbound,
+            max_clients: config.max_clients,
+            logins,
+            names: Arc::new(config.names.clone()),
+            hooks,
+        })
+    }
+
+    /// Wakes the loop up, so that it notices `running` being cleared.
+    pub fn waker(&self) -> Arc<Waker> {
+        self.waker.clone()
+    }
+
+    /// Serves connections, accepting new ones until `accepting` is cleared,
+    /// until `running` is cleared. Connections get a moment to flush what is
+    /// queued for them, then the ones still open are dropped and their
+    /// clients are left in the registry.
+    pub fn run(self, accepting: Arc<AtomicBool>, running: Arc<AtomicBool>) -> io::Result<()> {
+        let Self {
+            mut poll,
+            waker,
+            listener,
+            registered,
+            tx,
+            clients,
+            frame_limits,
+            heartbeat,
+            outbound_config,
+            max_clients,
+            logins,
+            names,
+            hooks,
+        } = self;
+        let pending: Arc<Mutex<HashSet<Token>>> = Arc::new(Mutex::new(HashSet::new()));
+
+        let mut connections: HashMap<Token, Connection> = HashMap::new();
+        let mut client_id_serial: i32 = 0;
+        let mut events = Events::with_capacity(1024);
+        let mut last_sweep = Instant::now();
+        let mut listening = Some((listener, registered));
+        let mut stopping: Option<Instant> = None;
+
+        loop {
+            if !accepting.load(Ordering::Relaxed)
+                && let Some((_, mut registered)) = listening.take()
+            {
+                let _ = poll.registry().deregister(&mut registered);
+                info!("No longer accepting connections");
+            }
+            if !running.load(Ordering::Relaxed) {
+                let since = *stopping.get_or_insert_with(Instant::now);
+                if connections.is_empty() || since.elapsed() > FLUSH_TIMEOUT {
+                    break;
+                }
+            }
+
+            if let Err(err) = poll.poll(&mut events, Some(SWEEP_INTERVAL)) {
+                if err.kind() == ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(err);
+            }
+
+            let mut finished = Vec::new();
+            for event in events.iter() {
+                match event.token() {
+                    LISTENER => {
+                        while let Some((listener, _)) = &listening {
+                            let stream = match listener.accept() {
+                                Ok((stream, _)) => stream,
+                                Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                                Err(err) => {
+                                    warn!("Connection failed: {}", err);
+                                    continue;
+                                }
+                            };
+                            let Ok(peer_addr) = stream.peer_addr() else {
+                                continue;
+                            };
+                            if clients.read().unwrap().len() >= max_clients {
+                                Client::refuse(stream, peer_addr);
+                                continue;
+                            }
+                            info!("Client {} connected", peer_addr);
+
+                            let id = client_id_serial;
+                            client_id_serial += 1;
+                            let token = Token(id as usize);
+
+                            let client = match stream
+                                .set_nonblocking(true)
+                                .and_then(|_| stream.try_clone())
+                            {
+                                Ok(clone) => Client::new(id, peer_addr, clone, outbound_config),
+                                Err(err) => {
+                                    warn!("Connection failed: {}", err);
+                                    continue;
+                                }
+                            };
+                            client.outbound.set_notifier(Notifier {
+                                token,
+                                waker: waker.clone(),
+                                pending: pending.clone(),
+                            });
+
+                            let mut connection = Connection {
+                                stream: TcpStream::from_std(stream),
+                                session: Session::new(
+                                    id,
+                                    peer_addr,
+                                    &client.outbound,
+                                    logins.clone(),
+                                    names.clone(),
+                                ),
+                                read_buffer: Vec::new(),
+                                write_buffer: Vec::new(),
+                                last_read: Instant::now(),
+                                reading: true,
+                                paused: false,
+                            };
+                            if let Err(err) = poll.registry().register(
+                                &mut connection.stream,
+                                token,
+                                Interest::READABLE | Interest::WRITABLE,
+                            ) {
+                                warn!("Connection failed: {}", err);
+                                continue;
+                            }
+
+                            clients.write().unwrap().insert(client);
+                            connections.insert(token, connection);
+                            hooks.connected(id, peer_addr);
+                        }
+                    }
+                    // Handled below together with the frames queued meanwhile
+                    WAKER => {}
+                    token => {
+                        let Some(connection) = connections.get_mut(&token) else {
+                            continue;
+                        };
+                        if event.is_readable() {
+                            connection.read(&tx, &clients, frame_limits, heartbeat);
+                        }
+                        if event.is_writable() && !connection.flush() {
+                            finished.push(token);
+                        }
+                    }
+                }
+            }
+
+            let woken = std::mem::take(&mut *pending.lock().unwrap());
+            for token in woken {
+                let Some(connection) = connections.get_mut(&token) else {
+                    continue;
+                };
+                connection.resume(&tx, &clients, frame_limits, heartbeat);
+                if !connection.flush() {
+                    finished.push(token);
+                }
+            }
+
+            if last_sweep.elapsed() >= SWEEP_INTERVAL {
+                last_sweep = Instant::now();
+                // Pings keep healthy clients talking, so silence means the peer is gone
+                for connection in connections.values_mut() {
+                    if connection.reading
+                        && !connection.paused
+                        && connection.last_read.elapsed() > heartbeat.timeout
+                    {
+                        connection.read_failed(
+                            ReadError::Io(ErrorKind::TimedOut.into()),
+                            &tx,
+                            &clients,
+                            heartbeat,
+                        );
+                    }
+                }
+            }
+
+            for token in finished {
+                let Some(mut connection) = connections.remove(&token) else {
+                    continue;
+                };
+                let _ = poll.registry().deregister(&mut connection.stream);
+                if connection.reading {
+                    let id = connection.session.id;
+                    info!("Client disconnected {:?}", connection.session.peer_addr);
+                    tx.disconnected(id);
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -1,28 +1,23 @@
 use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
     io::Read,
     net::TcpStream,
-    sync::{Arc, RwLock},
+    time::{SystemTime, UNIX_EPOCH},
 };
 
-use crate::Client;
-
-pub fn move_clients(
-    src: &Arc<RwLock<Vec<Client>>>,
-    predicate: impl Fn(&Client) -> bool,
-) -> Arc<RwLock<Vec<Client>>> {
-    let mut src_vec = src.write().unwrap();
-    let mut moved = Vec::new();
-
-    let mut i = src_vec.len();
-    while i > 0 {
-        i -= 1;
-        if predicate(&src_vec[i]) {
-            let client = src_vec.swap_remove(i); // MOVE out
-            moved.push(client); // now in destination
-        }
-    }
+use network_types::connection::{DecodeError, FRAME_HEADER_SIZE, Packet};
+
+#[derive(Debug)]
+pub enum ReadError {
+    Io(std::io::Error),
+    Decode(DecodeError),
+}
 
-    Arc::new(RwLock::new(moved))
+impl From<std::io::Error> for ReadError {
+    fn from(err: std::io::Error) -> Self {
+        ReadError::Io(err)
+    }
 }
 
 fn read_exact_bytes(stream: &mut TcpStream, size: usize) -> std::io::Result<Vec<u8>> {
@@ -31,11 +26,123 @@ fn read_exact_bytes(stream: &mut TcpStream, size: usize) -> std::io::Result<Vec<
     Ok(buf)
 }
 
-pub fn read_message(stream: &mut TcpStream) -> std::io::Result<Vec<u8>> {
+/// Reads and decodes one length-prefixed frame, refusing frames larger than
+/// `max_size` before allocating anything for them. Returns the packet
+/// together with its payload.
+pub fn read_message(
+    stream: &mut TcpStream,
+    max_size: usize,
+) -> Result<(Packet, Vec<u8>), ReadError> {
     // Read length header
-    let header = read_exact_bytes(stream, 4)?;
+    let header = read_exact_bytes(stream, FRAME_HEADER_SIZE)?;
     let len = u32::from_be_bytes(header.try_into().unwrap()) as usize;
+    if len > max_size {
+        return Err(ReadError::Decode(DecodeError::Oversized {
+            size: len,
+            max: max_size,
+        }));
+    }
 
     // Read full message body
-    read_exact_bytes(stream, len)
+    let payload = read_exact_bytes(stream, len)?;
+    let packet = Packet::try_from(&payload).map_err(ReadError::Decode)?;
+    Ok((packet, payload))
+}
+
+/// Takes one frame off the front of `buffer` once all of it has been
+/// received, see [`Packet::from_frame`]. Like [`read_message`], returns the
+/// packet together with its payload.
+pub fn take_message(
+    buffer: &mut Vec<u8>,
+    max_size: usize,
+) -> Result<Option<(Packet, Vec<u8>)>, DecodeError> {
+    let Some((packet, used)) = Packet::from_frame(buffer, max_size)? else {
+        return Ok(None);
+    };
+    let payload = buffer[FRAME_HEADER_SIZE..used].to_vec();
+    buffer.drain(..used);
+    Ok(Some((packet, payload)))
+}
+
+/// Unpredictable enough for seeds, not for anything that must not be
+/// guessed, see [`secret_u64`].
+pub fn random_u64() -> u64 {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u128(unix_time().as_nanos());
+    hasher.finish()
+}
+
+/// Drawn from the operating system's secure random source, for tokens that
+/// must not be guessed.
+pub fn secret_u64() -> u64 {
+    getrandom::u64().expect("the system random source is unavailable")
+}
+
+/// Time since the Unix epoch, zero if the clock is set before it.
+pub fn unix_time() -> std::time::Duration {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ping_frame() -> Vec<u8> {
+        Packet::Ping.serialize_with_header()
+    }
+
+    #[test]
+    fn take_message_waits_for_the_whole_frame() {
+        let frame = Packet::LoginRequest {
+            name: "fred".to_owned(),
+            credentials: network_types::connection::Credentials::None,
+        }
+        .serialize_with_header();
+        let mut buffer = Vec::new();
+        for &byte in &frame[..frame.len() - 1] {
+            buffer.push(byte);
+            assert!(matches!(take_message(&mut buffer, 1024), Ok(None)));
+        }
+        assert_eq!(buffer.len(), frame.len() - 1);
+
+        buffer.push(frame[frame.len() - 1]);
+        let (packet, payload) = take_message(&mut buffer, 1024).unwrap().unwrap();
+        assert!(matches!(packet, Packet::LoginRequest { name, .. } if name == "fred"));
+        assert_eq!(payload, frame[FRAME_HEADER_SIZE..]);
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn take_message_takes_one_frame_at_a_time() {
+        let mut buffer = [ping_frame(), Packet::Ack.serialize_with_header()].concat();
+        buffer.extend_from_slice(&ping_frame()[..2]);
+
+        let (packet, _) = take_message(&mut buffer, 1024).unwrap().unwrap();
+        assert!(matches!(packet, Packet::Ping));
+        let (packet, _) = take_message(&mut buffer, 1024).unwrap().unwrap();
+        assert!(matches!(packet, Packet::Ack));
+        assert!(matches!(take_message(&mut buffer, 1024), Ok(None)));
+        assert_eq!(buffer, ping_frame()[..2]);
+    }
+
+    #[test]
+    fn take_message_refuses_oversized_frames_from_the_header() {
+        let mut buffer = 2048u32.to_be_bytes().to_vec();
+        assert!(matches!(
+            take_message(&mut buffer, 1024),
+            Err(DecodeError::Oversized {
+                size: 2048,
+                max: 1024
+            })
+        ));
+    }
+
+    #[test]
+    fn take_message_refuses_garbage() {
+        let mut buffer = 3u32.to_be_bytes().to_vec();
+        buffer.extend_from_slice(&[0xff, 0xff, 0xff]);
+        assert!(take_message(&mut buffer, 1024).is_err());
+    }
 }
@@ -0,0 +1,27 @@
+mod auth;
+mod client;
+mod config;
+mod dispatcher;
+mod event_loop;
+mod helpers;
+mod listing;
+mod logger;
+mod login;
+mod metrics;
+mod names;
+mod outbound;
+mod registry;
+mod room;
+mod server;
+mod session;
+mod udp;
+
+pub use auth::{Anonymous, AuthError, Authenticator, CredentialsFile, SignedTokens};
+pub use client::Heartbeat;
+pub use config::{Auth, AuthMethod, ConfigError, Settings, usage};
+pub use logger::init_logger;
+pub use names::{NameError, NameRules};
+pub use outbound::{OutboundConfig, OverflowPolicy};
+pub use room::HostMigration;
+pub use server::{Hooks, IoMode, LocalAddrs, QueueLimits, Server, ServerBuilder, ServerConfig};
+pub use session::FrameLimits;
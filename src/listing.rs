@@ -0,0 +1,383 @@
+use std::{cmp::Reverse, collections::HashMap};
+
+use network_types::connection::{MatchInfo, MatchQuery, MatchSort, MatchState, Packet, Visibility};
+
+use crate::{
+    ServerConfig,
+    registry::{ClientRegistry, MatchRegistry},
+    room::RoomHandle,
+};
+
+/// Most tags a match may carry.
+const MAX_TAGS: usize = 8;
+/// Longest tag, in characters.
+const MAX_TAG_LEN: usize = 32;
+
+/// How the match list is served.
+#[derive(Debug, Clone)]
+pub struct ListingSettings {
+    /// Reported with every match, see [`MatchInfo::region`].
+    pub region: String,
+    /// Most entries in one [`Packet::MatchList`].
+    pub page_size: usize,
+}
+
+impl From<&ServerConfig> for ListingSettings {
+    fn from(config: &ServerConfig) -> Self {
+        Self {
+            region: config.region.clone(),
+            page_size: config.match_list_page_size,
+        }
+    }
+}
+
+/// Trims and lowercases the tags a match is created with, refusing more or
+/// longer ones than allowed.
+pub fn normalize_tags(tags: Vec<String>) -> Result<Vec<String>, String> {
+    if tags.len() > MAX_TAGS {
+        return Err(format!("A match takes at most {} tags", MAX_TAGS));
+    }
+    let mut normalized = Vec::with_capacity(tags.len());
+    for tag in tags {
+        let tag = tag.trim().to_lowercase();
+        if !(1..=MAX_TAG_LEN).contains(&tag.chars().count()) {
+            return Err(format!("Tags take 1 to {} characters", MAX_TAG_LEN));
+        }
+        if !normalized.contains(&tag) {
+            normalized.push(tag);
+        }
+    }
+    Ok(normalized)
+}
+
+/// What the match list shows of `room`, `None` unless it is public.
+pub fn listed(
+    room: &RoomHandle,
+    clients: &ClientRegistry,
+    settings: &ListingSettings,
+) -> Option<MatchInfo> {
+    if room.visibility != Visibility::Public {
+        return None;
+    }
+    Some(MatchInfo {
+        id: room.id,
+        name: room.name.clone(),
+        host: clients
+            .get(room.owner_id)
+            .map(|owner| owner.name.clone())
+            .unwrap_or_default(),
+        map: room.map.clone(),
+        state: if room.started {
+            MatchState::Started
+        } else {
+            MatchState::Waiting
+        },
+        players: room.players,
+        max_players: room.max_players,
+        locked: room.locked,
+        tags: room.tags.clone(),
+        region: settings.region.clone(),
+    })
+}
+
+/// Whether `info` passes the filters of `query`, paging aside.
+fn matches_query(info: &MatchInfo, query: &MatchQuery) -> bool {
+    (!query.not_started || info.state == MatchState::Waiting)
+        && (!query.has_free_slots || info.players < info.max_players)
+        && query.name_contains.as_ref().is_none_or(|needle| {
+            info.name
+                .to_lowercase()
+                .contains(&needle.trim().to_lowercase())
+        })
+        && query
+            .tags
+            .iter()
+            .all(|tag| info.tags.contains(&tag.trim().to_lowercase()))
+}
+
+/// The page of public matches `query` asks for, and how many matched in all.
+fn page(
+    matches: &MatchRegistry,
+    query: &MatchQuery,
+    clients: &ClientRegistry,
+    settings: &ListingSettings,
+) -> (Vec<MatchInfo>, u32) {
+    // The registry lists matches oldest first
+    let mut found = matches
+        .iter()
+        .filter_map(|room| listed(room, clients, settings))
+        .filter(|info| matches_query(info, query))
+        .collect::<Vec<_>>();
+    match query.sort {
+        MatchSort::Oldest => {}
+        MatchSort::Newest => found.reverse(),
+        MatchSort::MostPlayers => found.sort_by_key(|info| Reverse(info.players)),
+        MatchSort::FewestPlayers => found.sort_by_key(|info| info.players),
+        MatchSort::Name => found.sort_by_cached_key(|info| info.name.to_lowercase()),
+    }
+
+    let total = found.len() as u32;
+    let limit = match query.limit as usize {
+        0 => settings.page_size,
+        limit => limit.min(settings.page_size),
+    };
+    let page = found
+        .into_iter()
+        .skip(query.offset as usize)
+        .take(limit)
+        .collect();
+    (page, total)
+}
+
+/// The match list `query` asks for.
+pub fn snapshot(
+    matches: &MatchRegistry,
+    query: &MatchQuery,
+    draining: bool,
+    clients: &ClientRegistry,
+    settings: &ListingSettings,
+) -> Packet {
+    let (matches, total) = page(matches, query, clients, settings);
+    Packet::MatchList {
+        matches,
+        total,
+        draining,
+    }
+}
+
+/// Clients following the match list, at most once each. After the snapshot
+/// answering [`Packet::ListMatches`] they hear about every match passing
+/// their query's filters through [`Packet::MatchAdded`],
+/// [`Packet::MatchUpdated`] and [`Packet::MatchRemoved`], whatever page they
+/// asked for.
+#[derive(Debug, Default)]
+pub struct Subscribers {
+    queries: HashMap<i32, MatchQuery>,
+}
+
+impl Subscribers {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Subscribes `id`, replacing the query of an earlier subscription.
+    pub fn subscribe(&mut self, id: i32, query: MatchQuery) {
+        self.queries.insert(id, query);
+    }
+
+    pub fn unsubscribe(&mut self, id: i32) -> bool {
+        self.queries.remove(&id).is_some()
+    }
+
+    /// Tells subscribers about a match that was listed as `before` and now
+    /// is as `after`, `None` meaning not listed.
+    pub fn publish(
+        &self,
+        before: Option<&MatchInfo>,
+        after: Option<&MatchInfo>,
+        clients: &ClientRegistry,
+    ) {
+        if before == after {
+            return;
+        }
+        for (id, query) in self.queries.iter() {
+            let was = before.filter(|info| matches_query(info, query));
+            let is = after.filter(|info| matches_query(info, query));
+            let packet = match (was, is) {
+                (None, Some(info)) => Packet::MatchAdded { info: info.clone() },
+                (Some(_), Some(info)) => Packet::MatchUpdated { info: info.clone() },
+                (Some(info), None) => Packet::MatchRemoved { id: info.id },
+                (None, None) => continue,
+            };
+            if let Some(client) = clients.get(*id) {
+                client.outbound.send(&packet);
+            }
+        }
+    }
+
+    /// Sends every subscriber a fresh snapshot, for changes to the list as a
+    /// whole.
+    pub fn resend(
+        &self,
+        matches: &MatchRegistry,
+        draining: bool,
+        clients: &ClientRegistry,
+        settings: &ListingSettings,
+    ) {
+        for (id, query) in self.queries.iter() {
+            if let Some(client) = clients.get(*id) {
+                client
+                    .outbound
+                    .send(&snapshot(matches, query, draining, clients, settings));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, RwLock};
+
+    use network_types::connection::MatchOptions;
+
+    use super::*;
+    use crate::{
+        OutboundConfig, dispatcher::Dispatcher, metrics::Metrics, outbound::Outbound, room::Match,
+    };
+
+    fn info(name: &str, players: i32, started: bool, tags: &[&str]) -> MatchInfo {
+        MatchInfo {
+            id: 1,
+            name: name.to_owned(),
+            host: "fred".to_owned(),
+            map: None,
+            state: if started {
+                MatchState::Started
+            } else {
+                MatchState::Waiting
+            },
+            players,
+            max_players: 4,
+            locked: false,
+            tags: tags.iter().map(|tag| tag.to_string()).collect(),
+            region: String::new(),
+        }
+    }
+
+    fn settings(page_size: usize) -> ListingSettings {
+        ListingSettings {
+            region: "eu".to_owned(),
+            page_size,
+        }
+    }
+
+    /// Spawns a match for every `(name, players, visibility)`, oldest first.
+    fn registry(rooms: &[(&str, i32, Visibility)]) -> MatchRegistry {
+        let config = ServerConfig::default();
+        let clients = Arc::new(RwLock::new(ClientRegistry::new()));
+        let (dispatcher, _) = Dispatcher::new(16, Arc::new(Metrics::default()));
+        let mut matches = MatchRegistry::new();
+        for &(name, players, visibility) in rooms {
+            let id = matches.next_id();
+            let options = MatchOptions {
+                max_players: Some(8),
+                visibility,
+                ..MatchOptions::default()
+            };
+            let owner = Outbound::new(OutboundConfig::default());
+            let mut room = Match::new(id, -1, name.to_owned(), options, owner).spawn(
+                matches.unused_code(),
+                clients.clone(),
+                dispatcher.clone(),
+                (&config).into(),
+            );
+            room.players = players;
+            matches.insert(room);
+        }
+        matches
+    }
+
+    fn names(page: &[MatchInfo]) -> Vec<&str> {
+        page.iter().map(|info| info.name.as_str()).collect()
+    }
+
+    #[test]
+    fn default_query_matches_everything() {
+        let query = MatchQuery::default();
+        assert!(matches_query(&info("a", 4, true, &[]), &query));
+        assert!(matches_query(&info("b", 0, false, &["ctf"]), &query));
+    }
+
+    #[test]
+    fn query_filters_on_state_and_free_slots() {
+        let not_started = MatchQuery {
+            not_started: true,
+            ..MatchQuery::default()
+        };
+        assert!(matches_query(&info("a", 1, false, &[]), &not_started));
+        assert!(!matches_query(&info("a", 1, true, &[]), &not_started));
+
+        let has_free_slots = MatchQuery {
+            has_free_slots: true,
+            ..MatchQuery::default()
+        };
+        assert!(matches_query(&info("a", 3, false, &[]), &has_free_slots));
+        assert!(!matches_query(&info("a", 4, false, &[]), &has_free_slots));
+    }
+
+    #[test]
+    fn query_filters_on_name_ignoring_case_and_spaces() {
+        let query = MatchQuery {
+            name_contains: Some(" Night ".to_owned()),
+            ..MatchQuery::default()
+        };
+        assert!(matches_query(
+            &info("Friday NIGHT fun", 1, false, &[]),
+            &query
+        ));
+        assert!(!matches_query(&info("Friday fun", 1, false, &[]), &query));
+    }
+
+    #[test]
+    fn query_needs_every_tag() {
+        let query = MatchQuery {
+            tags: vec![" CTF".to_owned(), "ranked".to_owned()],
+            ..MatchQuery::default()
+        };
+        assert!(matches_query(
+            &info("a", 1, false, &["ranked", "ctf", "eu"]),
+            &query
+        ));
+        assert!(!matches_query(&info("a", 1, false, &["ctf"]), &query));
+    }
+
+    #[test]
+    fn page_lists_public_matches_in_the_order_asked() {
+        let matches = registry(&[
+            ("bravo", 2, Visibility::Public),
+            ("hidden", 5, Visibility::Unlisted),
+            ("alpha", 1, Visibility::Public),
+            ("charlie", 3, Visibility::Public),
+        ]);
+        let clients = ClientRegistry::new();
+        let page_of = |sort| {
+            let query = MatchQuery {
+                sort,
+                ..MatchQuery::default()
+            };
+            let (page, total) = page(&matches, &query, &clients, &settings(10));
+            assert_eq!(total, 3);
+            names(&page).join(" ")
+        };
+        assert_eq!(page_of(MatchSort::Oldest), "bravo alpha charlie");
+        assert_eq!(page_of(MatchSort::Newest), "charlie alpha bravo");
+        assert_eq!(page_of(MatchSort::MostPlayers), "charlie bravo alpha");
+        assert_eq!(page_of(MatchSort::FewestPlayers), "alpha bravo charlie");
+        assert_eq!(page_of(MatchSort::Name), "alpha bravo charlie");
+
+        let (page, _) = page(&matches, &MatchQuery::default(), &clients, &settings(10));
+        assert!(page.iter().all(|info| info.region == "eu"));
+    }
+
+    #[test]
+    fn page_skips_offset_and_caps_limit_at_page_size() {
+        let rooms = ["a", "b", "c", "d", "e"].map(|name| (name, 1, Visibility::Public));
+        let matches = registry(&rooms);
+        let clients = ClientRegistry::new();
+        let page_of = |offset, limit, page_size| {
+            let query = MatchQuery {
+                offset,
+                limit,
+                ..MatchQuery::default()
+            };
+            let (page, total) = page(&matches, &query, &clients, &settings(page_size));
+            assert_eq!(total, 5);
+            names(&page).join(" ")
+        };
+        assert_eq!(page_of(0, 0, 3), "a b c");
+        assert_eq!(page_of(0, 2, 3), "a b");
+        assert_eq!(page_of(1, 10, 3), "b c d");
+        assert_eq!(page_of(3, 0, 3), "d e");
+        assert_eq!(page_of(5, 0, 3), "");
+    }
+}
@@ -0,0 +1,250 @@
+use std::{
+    sync::{
+        Arc, Condvar, Mutex, RwLock,
+        atomic::{AtomicBool, AtomicU32, Ordering},
+    },
+    thread::{self, JoinHandle},
+};
+
+use crossbeam::channel::{Receiver, Sender, TrySendError, bounded};
+use log::info;
+use network_types::connection::{Credentials, DisconnectReason, ErrorCode, Packet, RequestKind};
+
+use crate::{
+    Authenticator, NameRules,
+    client::send_error,
+    dispatcher::{Dispatcher, Message},
+    metrics::Metrics,
+    outbound::Outbound,
+    registry::ClientRegistry,
+    session::ClientState,
+};
+
+/// Refused logins a connection may send before it is dropped.
+const MAX_LOGIN_FAILURES: u32 = 5;
+
+/// What a session shares with the login workers about its logins.
+#[derive(Debug, Default)]
+pub(crate) struct LoginState {
+    /// Set while a login waits for a worker. The session reads no further
+    /// frames meanwhile, so that requests sent after it find it done.
+    pending: Mutex<bool>,
+    finished: Condvar,
+    logged_in: AtomicBool,
+    failures: AtomicU32,
+}
+
+impl LoginState {
+    pub fn pending(&self) -> bool {
+        *self.pending.lock().unwrap()
+    }
+
+    /// Blocks until no login is pending.
+    pub fn wait(&self) {
+        let pending = self.pending.lock().unwrap();
+        let _done = self
+            .finished
+            .wait_while(pending, |pending| *pending)
+            .unwrap();
+    }
+
+    pub fn logged_in(&self) -> bool {
+        self.logged_in.load(Ordering::Relaxed)
+    }
+
+    fn begin(&self) {
+        *self.pending.lock().unwrap() = true;
+    }
+
+    fn finish(&self) {
+        *self.pending.lock().unwrap() = false;
+        self.finished.notify_all();
+    }
+}
+
+/// A [`Packet::LoginRequest`] waiting to be checked.
+pub(crate) struct Login {
+    pub id: i32,
+    pub request_id: Option<u32>,
+    pub name: String,
+    pub credentials: Credentials,
+    pub outbound: Outbound,
+    pub state: Arc<LoginState>,
+}
+
+/// Bounded queue of logins checked by a few worker threads, so that a slow
+/// [`Authenticator`] never holds up the threads serving sockets.
+#[derive(Debug, Clone)]
+pub(crate) struct Logins {
+    tx: Sender<Login>,
+    metrics: Arc<Metrics>,
+}
+
+impl Logins {
+    /// Starts `workers` threads checking logins. They stop once every
+    /// [`Logins`] handle is dropped.
+    pub fn spawn(
+        workers: usize,
+        capacity: usize,
+        authenticator: Arc<dyn Authenticator>,
+        names: Arc<NameRules>,
+        clients: Arc<RwLock<ClientRegistry>>,
+        dispatcher: Dispatcher,
+        metrics: Arc<Metrics>,
+    ) -> (Self, Vec<JoinHandle<()>>) {
+        let (tx, rx) = bounded(capacity);
+        metrics.login.set_capacity(capacity);
+        let handles = (0..workers)
+            .map(|_| {
+                let worker = Worker {
+                    authenticator: authenticator.clone(),
+                    names: names.clone(),
+                    clients: clients.clone(),
+                    tx: dispatcher.clone(),
+                };
+                let rx = rx.clone();
+                thread::spawn(move || worker.run(rx))
+            })
+            .collect();
+        (Self { tx, metrics }, handles)
+    }
+
+    /// Queues `login`, answering it with [`ErrorCode::ServerBusy`] when the
+    /// workers are saturated.
+    pub fn submit(&self, login: Login, clients: &Arc<RwLock<ClientRegistry>>) {
+        login.state.begin();
+        match self.tx.try_send(login) {
+            Ok(()) => self.metrics.login.observe(self.tx.len()),
+            Err(TrySendError::Full(login) | TrySendError::Disconnected(login)) => {
+                self.metrics.login.overflow();
+                login.state.finish();
+                send_error(
+                    login.id,
+                    login.request_id,
+                    clients,
+                    RequestKind::Login,
+                    ErrorCode::ServerBusy,
+                    "Too many logins at once, try again later".to_owned(),
+                );
+            }
+        }
+    }
+}
+
+/// What a login worker checks logins with.
+struct Worker {
+    authenticator: Arc<dyn Authenticator>,
+    names: Arc<NameRules>,
+    clients: Arc<RwLock<ClientRegistry>>,
+    tx: Dispatcher,
+}
+
+impl Worker {
+    fn run(self, logins: Receiver<Login>) {
+        for login in logins.iter() {
+            self.check(login);
+        }
+    }
+
+    /// Checks one login and answers it, then lets its session go on.
+    fn check(&self, login: Login) {
+        let Login {
+            id,
+            request_id,
+            name,
+            credentials,
+            outbound,
+            state,
+        } = login;
+        match self.answer(id, &name, &credentials, &state) {
+            Some(Ok(packet)) => outbound.send(&packet.reply_to(request_id)),
+            Some(Err((code, message))) => send_error(
+                id,
+                request_id,
+                &self.clients,
+                RequestKind::Login,
+                code,
+                message,
+            ),
+            None => {}
+        }
+
+        if state.failures.load(Ordering::Relaxed) >= MAX_LOGIN_FAILURES {
+            let message = format!("{} failed logins", MAX_LOGIN_FAILURES);
+            info!("Client ({}) disconnected by server: {}", id, message);
+            outbound.close_with(&Packet::Disconnect {
+                reason: DisconnectReason::TooManyAttempts,
+                message,
+            });
+            self.tx.disconnected(id);
+        }
+
+        // Replies go out first, so that they come before whatever the
+        // session reads next
+        state.finish();
+        outbound.notify();
+    }
+
+    /// The [`Packet::Login`] or the error answering a login, `None` when the
+    /// client is gone.
+    fn answer(
+        &self,
+        id: i32,
+        name: &str,
+        credentials: &Credentials,
+        state: &LoginState,
+    ) -> Option<Result<Packet, (ErrorCode, String)>> {
+        let name = match self.authenticator.authenticate(name, credentials) {
+            Ok(name) => name,
+            Err(err) => {
+                state.failures.fetch_add(1, Ordering::Relaxed);
+                return Some(Err((
+                    ErrorCode::AuthenticationFailed,
+                    format!("Login as {:?} refused: {}", name, err),
+                )));
+            }
+        };
+        let name = match self.names.check(&name) {
+            Ok(name) => name.to_owned(),
+            Err(err) => {
+                return Some(Err((
+                    ErrorCode::InvalidName,
+                    format!("Name {:?} {}", name, err),
+                )));
+            }
+        };
+
+        let mut clients = self.clients.write().unwrap();
+        let client = clients.get(id)?;
+        let (previous, match_id, client_state) =
+            (client.name.clone(), client.match_id, client.state);
+        let proven = self.authenticator.proves_identity();
+        let Some(name) = clients.claim_name(id, &name, &self.names, proven) else {
+            return Some(Err((
+                ErrorCode::InvalidName,
+                format!("Name {:?} is already in use", name),
+            )));
+        };
+        if client_state == ClientState::Guest {
+            clients.set_state(id, ClientState::Menu);
+        }
+        if name != previous && match_id != -1 {
+            info!("Client ({}) renamed from {:?} to {:?}", id, previous, name);
+            let changed = Packet::NameChanged {
+                id,
+                name: name.clone(),
+            };
+            clients
+                .members(match_id)
+                .filter(|c| c.id != id)
+                .for_each(|c| c.outbound.send(&changed));
+            self.tx.notify(Message::Renamed {
+                id,
+                match_id,
+                previous,
+            });
+        }
+        state.logged_in.store(true, Ordering::Relaxed);
+        Some(Ok(Packet::Login { id, name }))
+    }
+}
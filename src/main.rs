@@ -1,647 +1,123 @@
-mod helpers;
-mod udp;
+use std::process::ExitCode;
 
-use crossbeam::channel::{Receiver, Sender, unbounded};
-use network_types::connection::Packet;
-use std::{
-    io::{Read, Write},
-    net::{TcpListener, TcpStream},
-    sync::{
-        Arc, RwLock, RwLockReadGuard,
-        atomic::{AtomicBool, Ordering},
-    },
-    thread::{self, JoinHandle},
-    time::Duration,
-};
+use log::info;
+use network_manager::{ConfigError, Server, ServerBuilder, Settings, init_logger, usage};
 
-pub struct Client {
-    id: i32,
-    match_id: i32,
-    name: String,
-    stream: TcpStream,
-    running: Arc<AtomicBool>,
-    thread: Option<JoinHandle<()>>,
-}
-
-#[derive(Debug)]
-pub enum ClientState {
-    Menu,
-    MatchClient,
-    MatchHost,
-    InGame,
-}
-
-impl Client {
-    pub fn new(id: i32, stream: TcpStream) -> Self {
-        Self {
-            id,
-            stream,
-            match_id: -1,
-            name: String::new(),
-            running: Arc::new(AtomicBool::new(true)),
-            thread: None,
+fn main() -> ExitCode {
+    let settings = match Settings::load(std::env::args().skip(1)) {
+        Ok(settings) => settings,
+        Err(ConfigError::Help) => {
+            print!("{}", usage());
+            return ExitCode::SUCCESS;
         }
-    }
-
-    fn send_message_to(id: i32, clients: &Arc<RwLock<Vec<Client>>>, message: &[u8]) {
-        let mut stream = clients
-            .read()
-            .unwrap()
-            .iter()
-            .find(|c| c.id == id)
-            .unwrap()
-            .stream
-            .try_clone()
-            .unwrap();
-
-        let len = message.len() as u32;
-        let mut buf = Vec::with_capacity(4 + message.len());
-
-        buf.extend_from_slice(&len.to_be_bytes());
-        buf.extend_from_slice(message);
-
-        match stream.write(&buf[..]) {
-            Ok(_) => {
-                println!("Sended Message to {}", id);
-            }
-            Err(err) => println!("Client ({}) not available: {:?}", id, err),
+        Err(err) => {
+            eprintln!("Configuration error: {}", err);
+            eprintln!("Run with --help to list the settings.");
+            return ExitCode::from(2);
         }
-    }
-
-    fn send_message(id: i32, clients: &Arc<RwLock<Vec<Client>>>, message: &[u8]) {
-        let clients_guard = clients.read().unwrap();
-        let mut clients_snapshot = {
-            let match_id = clients_guard.iter().find(|c| c.id == id).unwrap().match_id;
-            clients_guard
-                .iter()
-                .filter(|c| {
-                    return c.id != id && c.match_id == match_id;
-                })
-                .map(|c| (c.name.clone(), c.stream.try_clone().unwrap()))
-                .collect::<Vec<_>>()
-        };
-
-        let len = message.len() as u32;
-        let mut buf = Vec::with_capacity(4 + message.len());
-
-        buf.extend_from_slice(&len.to_be_bytes());
-        buf.extend_from_slice(message);
-
-        for (name, c) in clients_snapshot.iter_mut() {
-            match c.write(&buf[..]) {
-                Ok(_) => {}
-                Err(err) => println!("Client ({}) not available: {:?}", name, err),
-            }
+    };
+    init_logger(settings.log_level);
+
+    let authenticator = match settings.auth.authenticator() {
+        Ok(authenticator) => authenticator,
+        Err(err) => {
+            eprintln!("Configuration error: {}", err);
+            return ExitCode::from(2);
         }
-    }
-
-    pub fn start(
-        mut self,
-        tx: Sender<Message>,
-        clients: Arc<RwLock<Vec<Client>>>,
-        // match_list: RwLockReadGuard<Vec<Match>>,
-    ) -> Self {
-        let id = self.id.clone();
-        let running = self.running.clone();
-        let mut stream = self.stream.try_clone().unwrap();
-        self.thread = Some(thread::spawn(move || {
-            let peer_addr = stream.peer_addr().unwrap();
-            let mut state = ClientState::Menu;
-            while running.load(Ordering::Relaxed) {
-                let buffer = {
-                    match helpers::read_message(&mut stream) {
-                        Ok(b) => b,
-                        Err(_) => {
-                            tx.send(Message::Disconnected { id }).unwrap();
-                            println!("Client disconnected {:?}", peer_addr);
-                            break;
-                        }
-                    }
-                };
-
-                let packet = Packet::from(buffer.as_slice());
-
-                println!("Packet({}={:?}={}) {:?}", id, state, peer_addr, packet);
-
-                match state {
-                    ClientState::Menu => {
-                        match packet {
-                            Packet::LoginRequest { name } => {
-                                clients.write().unwrap().iter_mut().for_each(|client| {
-                                    if client.id == id {
-                                        client.name = name.clone();
-                                    }
-                                });
-                                stream
-                                    .write_all(
-                                        Packet::Login { id, name }
-                                            .serialize_with_header()
-                                            .as_slice(),
-                                    )
-                                    .unwrap();
-                            }
-                            Packet::RemoveFromListMatches => {
-                                tx.send(Message::RemoveFromListMatches { id }).unwrap()
-                            }
-                            Packet::ListMatches => tx.send(Message::ListMatches { id }).unwrap(),
-                            Packet::JoinMatch { room_id } => {
-                                println!("Client is MatchClient");
-                                tx.send(Message::JoinMatch { id, room_id }).unwrap();
-                                state = ClientState::MatchClient;
-                            }
-                            Packet::NewMatch { room_name } => {
-                                tx.send(Message::NewMatch { id, room_name }).unwrap();
-                                state = ClientState::MatchHost;
-                            }
-                            _ => continue,
-                        };
-                    }
-                    ClientState::MatchClient => {
-                        match packet {
-                            Packet::LeaveMatch { room_id } => {
-                                tx.send(Message::LeaveMatch { id, room_id }).unwrap();
-                                state = ClientState::Menu;
-                                continue;
-                            }
-                            Packet::RemoteObjectCall {
-                                id,
-                                object_id,
-                                method,
-                                params,
-                                broadcast,
-                            } => {
-                                if !broadcast {
-                                    Self::send_message_to(id, &clients, buffer.as_slice());
-                                    continue;
-                                }
-                                println!("calling send_all");
-                            }
-                            Packet::DespawnRemoteObject { id, object_id } => {
-                                println!(
-                                    "Packet::DespawnRemoteObject {{ id: {}, object_id: {} }}",
-                                    id, object_id
-                                );
-                            }
-                            _ => {}
-                        };
-                        // TODO Change this to only players in the same match
-                        Self::send_message(id, &clients, buffer.as_slice());
-                    }
-                    ClientState::MatchHost => {
-                        match packet {
-                            Packet::StartMatch { room_id, map } => {
-                                // TODO Start Match
-                                tx.send(Message::StartMatch { id, room_id, map }).unwrap();
-                            }
-                            Packet::DeleteMatch { room_id } => {
-                                println!("Delete Match {} = {}", id, room_id);
-                                tx.send(Message::DeleteMatch { id, room_id }).unwrap();
-                                state = ClientState::Menu;
-                                continue;
-                            }
-                            Packet::SpawnPlayers { room_id, positions } => {
-                                println!("SpawnPlayers");
-                                if positions.len() == 0 {
-                                    eprintln!("Invalid spaws for {}", room_id);
-                                    continue;
-                                }
-                                let clients_guard = clients.read().unwrap();
-                                let clients_snapshot = {
-                                    clients_guard
-                                        .iter()
-                                        .filter(|c| c.match_id == room_id)
-                                        .map(|c| (c.name.clone(), c.stream.try_clone().unwrap()))
-                                        .collect::<Vec<_>>()
-                                };
-                                let mut index = 0;
-                                for (name, mut stream) in clients_snapshot {
-                                    let spawn = positions[index].clone();
-
-                                    println!("Spawn {:?} for {}", spawn, name);
-
-                                    let message =
-                                        Packet::Spawn { position: spawn }.serialize_with_header();
-                                    match stream.write(message.as_slice()) {
-                                        Ok(_) => {
-                                            index = index + 1;
-                                            if index >= positions.len() {
-                                                index = 0;
-                                            }
-                                        }
-                                        Err(err) => {
-                                            println!(
-                                                "Client Spawn Message ({}) not available: {:?}",
-                                                name, err
-                                            )
-                                        }
-                                    }
-                                }
-                                continue;
-                            }
-                            Packet::RemoteObjectCall {
-                                id,
-                                object_id,
-                                method,
-                                params,
-                                broadcast,
-                            } => {
-                                if !broadcast {
-                                    Self::send_message_to(id, &clients, buffer.as_slice());
-                                    continue;
-                                }
-                            }
-                            Packet::DespawnRemoteObject { id, object_id } => {
-                                println!(
-                                    "Packet::DespawnRemoteObject {{ id: {}, object_id: {} }}",
-                                    id, object_id
-                                );
-                            }
-                            packet => {
-                                println!("Ingored Packaet {:?}", packet);
-                            }
-                        };
-                        // TODO Change this to only players in the same match
-                        Self::send_message(id, &clients, buffer.as_slice());
-                    }
-                    ClientState::InGame => {
-                        Self::send_message(id, &clients, buffer.as_slice());
-                    }
-                }
-            }
-        }));
-        self
-    }
-
-    pub fn join(&mut self) {
-        self.running.store(false, Ordering::Relaxed);
-        let _ = self.stream.shutdown(std::net::Shutdown::Both);
-        match self.thread.take() {
-            Some(handler) => {
-                let _ = handler.join();
-            }
-            None => {}
-        };
-    }
-}
-
-#[derive(Debug)]
-pub struct Match {
-    id: i32,
-    owner_id: i32,
-    name: String,
-    clients: Vec<i32>,
-    clients_sockets: Vec<TcpStream>,
-    started: bool,
-}
-
-#[derive(serde::Serialize, Debug, Clone)]
-pub enum Message {
-    Ping,
-    RemoveFromListMatches { id: i32 },
-    ListMatches { id: i32 },
-    JoinMatch { id: i32, room_id: i32 },
-    NewMatch { id: i32, room_name: String },
-    DeleteMatch { id: i32, room_id: i32 },
-    LeaveMatch { id: i32, room_id: i32 },
-    StartMatch { id: i32, room_id: i32, map: String },
-    Disconnected { id: i32 },
-}
-
-fn notify_all_match_list(
-    clients_on_match_list: &Vec<i32>,
-    match_list: RwLockReadGuard<Vec<Match>>,
-    clients: RwLockReadGuard<Vec<Client>>,
-) {
-    if clients_on_match_list.len() > 0 {
-        let match_list_packet = Packet::MatchList {
-            matches: match_list
-                .iter()
-                .map(|m| (m.id, m.name.clone(), m.clients.len() as i32))
-                .collect(),
-        };
+    };
+
+    let builder = ServerBuilder::new()
+        .config(settings.server)
+        .authenticator(authenticator);
+    let server = match serve(builder) {
+        Ok(server) => server,
+        Err(err) => {
+            eprintln!("{}", err);
+            return ExitCode::FAILURE;
+        }
+    };
 
-        for id in clients_on_match_list.iter() {
-            clients.iter().for_each(|client| {
-                if client.id == *id {
-                    client
-                        .stream
-                        .try_clone()
-                        .unwrap()
-                        .write(match_list_packet.serialize_with_header().as_slice())
-                        .unwrap();
-                }
-            });
+    match server.shutdown() {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("Server stopped: {}", err);
+            ExitCode::FAILURE
         }
     }
 }
 
-fn set_client_match_id(id: i32, match_id: i32, clients: &Arc<RwLock<Vec<Client>>>) {
-    let mut clients = clients.write().unwrap();
-    let client = clients.iter_mut().find(|c| c.id == id).unwrap();
-    client.match_id = match_id;
-}
-
-fn main() -> std::io::Result<()> {
-    let listener = TcpListener::bind("0.0.0.0:7878")?;
-    println!("Server listening on 0.0.0.0:7878");
-
-    let mut client_id_serial: i32 = 0;
-    // TODO Remove Disconnected Clients
-    let clients: Arc<RwLock<Vec<Client>>> = Arc::new(RwLock::new(Vec::new()));
-    let matches: Arc<RwLock<Vec<Match>>> = Arc::new(RwLock::new(Vec::new()));
-
-    let (tx, rx): (Sender<Message>, Receiver<Message>) = unbounded();
-
-    let main_loop_clients = clients.clone();
-    let main_loop = thread::spawn(move || {
-        let mut room_id_serial: i32 = 0;
-
-        let mut clients_on_match_list = Vec::new();
-        loop {
-            match rx.recv_timeout(Duration::from_millis(1)) {
-                Ok(message) => match message {
-                    Message::Ping => continue,
-                    Message::RemoveFromListMatches { id } => {
-                        if let Some(pos) = clients_on_match_list.iter().position(|v| *v == id) {
-                            clients_on_match_list.remove(pos);
-                        }
-                    }
-                    Message::ListMatches { id } => {
-                        clients_on_match_list.push(id);
-
-                        notify_all_match_list(
-                            &vec![id],
-                            matches.read().unwrap(),
-                            main_loop_clients.read().unwrap(),
-                        );
-                    }
-                    Message::NewMatch { id, room_name } => {
-                        room_id_serial = room_id_serial + 1;
-                        let match_id = room_id_serial;
-
-                        let clients = main_loop_clients.read().unwrap();
-
-                        {
-                            let mut m = matches.write().unwrap();
-
-                            let owner = clients.iter().find(|client| client.id == id).unwrap();
-                            let mut owner_stream = owner.stream.try_clone().unwrap();
-
-                            // Notify owner that the Match was created
-                            owner_stream
-                                .write(
-                                    Packet::MatchCreated {
-                                        id: match_id,
-                                        owner_id: id,
-                                        room_name: room_name.clone(),
-                                    }
-                                    .serialize_with_header()
-                                    .as_slice(),
-                                )
-                                .unwrap();
-
-                            m.push(Match {
-                                id: match_id,
-                                owner_id: id.clone(),
-                                name: room_name.clone(),
-                                clients: vec![id.clone()],
-                                clients_sockets: vec![owner_stream],
-                                started: false,
-                            });
-                        }
-
-                        // Notify all clients on Match List about this new Match
-                        notify_all_match_list(
-                            &clients_on_match_list,
-                            matches.read().unwrap(),
-                            clients,
-                        );
-
-                        set_client_match_id(id, match_id, &main_loop_clients);
-                    }
-                    Message::JoinMatch { id, room_id } => {
-                        clients_on_match_list = clients_on_match_list
-                            .into_iter()
-                            .filter(|user_id| user_id.clone() != id)
-                            .collect();
-
-                        set_client_match_id(id, room_id, &main_loop_clients);
-
-                        for m in matches.write().unwrap().iter_mut() {
-                            println!("m.id {} == room_id {}", m.id, room_id);
-                            if m.id == room_id {
-                                m.clients.push(id);
-
-                                let clients = main_loop_clients.read().unwrap();
-
-                                let (
-                                    joined_client_id,
-                                    joined_client_name,
-                                    mut joined_client_stream,
-                                ) = {
-                                    let client =
-                                        clients.iter().find(|client| client.id == id).unwrap();
-                                    (
-                                        client.id,
-                                        client.name.clone(),
-                                        client.stream.try_clone().unwrap(),
-                                    )
-                                };
-
-                                // Add client to socket
-                                m.clients_sockets
-                                    .push(joined_client_stream.try_clone().unwrap());
-
-                                for client_id in m.clients.iter() {
-                                    // Notify client that he joined successfully
-                                    let client = clients
-                                        .iter()
-                                        .find(|client| client.id == *client_id)
-                                        .unwrap();
-
-                                    println!("Sending MatchJoined for {}", *client_id);
-
-                                    // Tell new Client about the other clients
-                                    joined_client_stream
-                                        .write(
-                                            Packet::MatchJoined {
-                                                id: room_id,
-                                                user_id: client.id,
-                                                user_name: client.name.clone(),
-                                                room_name: m.name.clone(),
-                                            }
-                                            .serialize_with_header()
-                                            .as_slice(),
-                                        )
-                                        .unwrap();
-
-                                    if client.id != id {
-                                        // Tell room clients about the new client
-                                        client
-                                            .stream
-                                            .try_clone()
-                                            .unwrap()
-                                            .write(
-                                                Packet::MatchJoined {
-                                                    id: room_id,
-                                                    user_id: joined_client_id,
-                                                    user_name: joined_client_name.clone(),
-                                                    room_name: m.name.clone(),
-                                                }
-                                                .serialize_with_header()
-                                                .as_slice(),
-                                            )
-                                            .unwrap();
-                                    }
-                                }
-                                break;
-                            }
-                        }
-                    }
-                    Message::DeleteMatch { id, room_id } => {
-                        let clients = main_loop_clients.read().unwrap();
-                        matches.write().unwrap().retain(|m| {
-                            if m.id == room_id {
-                                println!("Deleted Match {}", room_id);
-                                for client_id in m.clients.iter() {
-                                    clients.iter().for_each(|client| {
-                                        if client.id == *client_id && client.id != id {
-                                            client
-                                                .stream
-                                                .try_clone()
-                                                .unwrap()
-                                                .write(
-                                                    Packet::MatchDeleted
-                                                        .serialize_with_header()
-                                                        .as_slice(),
-                                                )
-                                                .unwrap();
-                                        }
-                                    });
-                                }
-                                return false;
-                            }
-                            return true;
-                        });
-                        notify_all_match_list(
-                            &clients_on_match_list,
-                            matches.read().unwrap(),
-                            clients,
-                        );
-                        set_client_match_id(id, -1, &main_loop_clients);
-                    }
-                    Message::LeaveMatch { id, room_id } => {
-                        matches.write().unwrap().iter_mut().for_each(|m| {
-                            if m.id == room_id {
-                                let (name, client_peer_addr) = {
-                                    let clients = main_loop_clients.read().unwrap();
-                                    let c = clients.iter().find(|client| client.id == id).unwrap();
-                                    (c.name.clone(), c.stream.peer_addr().unwrap())
-                                };
-                                // Removes Client from Room
-                                m.clients.retain(|client| *client != id);
-                                m.clients_sockets
-                                    .retain(|s| s.peer_addr().unwrap() != client_peer_addr);
-
-                                // Tell Everybody
-                                m.clients_sockets.iter_mut().for_each(|stream| {
-                                    let _ = stream.write(
-                                        Packet::MatchLeaved {
-                                            user_id: id,
-                                            user_name: name.clone(),
-                                        }
-                                        .serialize_with_header()
-                                        .as_slice(),
-                                    );
-                                });
-                                // clients.iter().for_each(|client| {
-                                //     m.clients.iter().for_each(|id| {
-                                //         if *id == client.id {
-                                //             let _ = client.stream.try_clone().unwrap().write(
-                                //                 Packet::MatchLeaved {
-                                //                     user_id: client.id,
-                                //                     user_name: client.name.clone(),
-                                //                 }
-                                //                 .serialize_with_header()
-                                //                 .as_slice(),
-                                //             );
-                                //         }
-                                //     });
-                                // });
-                            }
-                        });
-                        set_client_match_id(id, -1, &main_loop_clients);
-                    }
-                    Message::Disconnected { id } => {
-                        main_loop_clients.write().unwrap().retain(|c| {
-                            if c.id == id {
-                                if c.match_id != -1 {
-                                    let mut matches = matches.write().unwrap();
-                                    let room = matches.iter_mut().find(|m| m.id == c.match_id);
-                                    if let Some(room) = room {
-                                        room.clients.retain(|mc| *mc != id);
-                                        // TODO Notify all that it was disconnected
-                                        // TODO Delete Match if empty
-                                    }
-                                }
-                                return false;
-                            }
-                            return true;
-                        });
-                        // TODO Call DeleteMatch or LeaveMatch
-                    }
-                    Message::StartMatch { id, room_id, map } => {
-                        {
-                            let mut matches = matches.write().unwrap();
-                            let room = matches.iter_mut().find(|m| m.id == room_id).unwrap();
-                            room.started = true;
-                        }
-                        // Notify all clients on Match List about this new Match
-                        notify_all_match_list(
-                            &clients_on_match_list,
-                            matches.read().unwrap(),
-                            main_loop_clients.read().unwrap(),
-                        );
-                        // TODO Check it latter
-                        // room.players =
-                        //     move_clients(&main_loop_clients, |c| room.clients.contains(&c.id));
-                    }
-                },
-                _ => {}
-            }
-        }
-    });
-
-    let udp_is_running = Arc::new(AtomicBool::new(true));
-    let udp_main_loop = udp::server("0.0.0.0:7879".to_owned(), udp_is_running.clone()).unwrap();
-
-    for stream in listener.incoming() {
-        match stream {
-            Ok(stream) => {
-                println!("Client {} connected", stream.peer_addr().unwrap());
-
-                clients
-                    .write()
-                    .unwrap()
-                    .push(Client::new(client_id_serial, stream).start(tx.clone(), clients.clone()));
-
-                client_id_serial = client_id_serial + 1;
+/// Starts the server and blocks until SIGINT or SIGTERM, or until it is
+/// drained. SIGUSR1 starts draining, SIGUSR2 cancels it.
+#[cfg(unix)]
+fn serve(builder: ServerBuilder) -> Result<Server, String> {
+    use log::warn;
+    use signal_hook::{
+        consts::{SIGINT, SIGTERM, SIGUSR1, SIGUSR2},
+        iterator::Signals,
+    };
+
+    let mut signals = Signals::new([SIGINT, SIGTERM, SIGUSR1, SIGUSR2])
+        .map_err(|err| format!("Cannot handle signals: {}", err))?;
+
+    // Once drained there is nothing left to wait for
+    let signals_handle = signals.handle();
+    let server = builder
+        .on_drained(move || signals_handle.close())
+        .start()
+        .map_err(|err| format!("Cannot start the server: {}", err))?;
+
+    for signal in signals.forever() {
+        match signal {
+            SIGUSR1 => server.set_draining(true),
+            SIGUSR2 => server.set_draining(false),
+            _ => {
+                info!("Received signal {}, shutting down", signal);
+                break;
             }
-            Err(e) => eprintln!("Connection failed: {}", e),
         }
     }
+    // A second signal skips the grace period
+    std::thread::spawn(move || {
+        if signals.forever().next().is_some() {
+            warn!("Received another signal, exiting now");
+            std::process::exit(1);
+        }
+    });
+    Ok(server)
+}
 
-    udp_is_running.store(false, Ordering::Relaxed);
-
-    for t in udp_main_loop {
-        let _ = t.join();
+/// Starts the server and blocks until Ctrl-C or SIGTERM. There are no
+/// signals to drain with, embedders use [`Server::set_draining`].
+#[cfg(not(unix))]
+fn serve(builder: ServerBuilder) -> Result<Server, String> {
+    use std::{
+        sync::{
+            Arc,
+            atomic::{AtomicBool, Ordering},
+        },
+        thread,
+        time::Duration,
+    };
+
+    use signal_hook::{
+        consts::{SIGINT, SIGTERM},
+        flag,
+    };
+
+    let stop = Arc::new(AtomicBool::new(false));
+    for signal in [SIGINT, SIGTERM] {
+        // Registered first, so that a second signal skips the grace period
+        flag::register_conditional_shutdown(signal, 1, stop.clone())
+            .and_then(|_| flag::register(signal, stop.clone()))
+            .map_err(|err| format!("Cannot handle signals: {}", err))?;
     }
 
-    main_loop.join().unwrap();
-
-    for client in clients.write().unwrap().iter() {
-        client.join();
+    let server = builder
+        .start()
+        .map_err(|err| format!("Cannot start the server: {}", err))?;
+    while !stop.load(Ordering::SeqCst) {
+        thread::sleep(Duration::from_millis(100));
     }
-
-    Ok(())
+    info!("Received a signal, shutting down");
+    Ok(server)
 }
@@ -0,0 +1,80 @@
+use crossbeam::channel::{Receiver, RecvTimeoutError};
+use log::info;
+use std::{
+    sync::{
+        Arc,
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+/// Load figures for one bounded queue, updated by whoever pushes into it.
+#[derive(Debug, Default)]
+pub struct QueueMetrics {
+    capacity: AtomicUsize,
+    depth: AtomicUsize,
+    /// Deepest the queue got since the last report.
+    peak: AtomicUsize,
+    /// Items turned away because the queue was full.
+    overflowed: AtomicU64,
+}
+
+impl QueueMetrics {
+    pub fn set_capacity(&self, capacity: usize) {
+        self.capacity.store(capacity, Ordering::Relaxed);
+    }
+
+    /// Records the depth of the queue right after a push.
+    pub fn observe(&self, depth: usize) {
+        self.depth.store(depth, Ordering::Relaxed);
+        self.peak.fetch_max(depth, Ordering::Relaxed);
+    }
+
+    pub fn overflow(&self) {
+        self.overflowed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn report(&self, name: &str, overflow: &str) -> String {
+        format!(
+            "{} {}/{} (peak {}, {} {})",
+            name,
+            self.depth.load(Ordering::Relaxed),
+            self.capacity.load(Ordering::Relaxed),
+            self.peak.swap(0, Ordering::Relaxed),
+            self.overflowed.load(Ordering::Relaxed),
+            overflow
+        )
+    }
+}
+
+/// Server-wide counters, shared by every thread that feeds a bounded queue.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    /// Lobby requests waiting for the dispatcher. Overflow is rejected.
+    pub dispatcher: QueueMetrics,
+    /// UDP datagrams waiting for a relay worker. Overflow is dropped.
+    pub udp_relay: QueueMetrics,
+    /// Logins waiting for a login worker. Overflow is rejected.
+    pub login: QueueMetrics,
+}
+
+impl Metrics {
+    /// Logs the queue figures every `interval` until `stop` is dropped.
+    pub fn spawn_reporter(
+        self: Arc<Self>,
+        interval: Duration,
+        stop: Receiver<()>,
+    ) -> JoinHandle<()> {
+        thread::spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) = stop.recv_timeout(interval) {
+                info!(
+                    "Queues: {}, {}, {}",
+                    self.dispatcher.report("dispatcher", "rejected"),
+                    self.udp_relay.report("udp relay", "dropped"),
+                    self.login.report("login", "rejected")
+                );
+            }
+        })
+    }
+}
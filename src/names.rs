@@ -0,0 +1,157 @@
+use std::fmt;
+
+/// What a display name may look like. Names are trimmed first, then must be
+/// `min_len..=max_len` characters of letters, digits and `extra_chars`, and
+/// must not be one of `reserved`, ignoring case.
+#[derive(Debug, Clone)]
+pub struct NameRules {
+    pub min_len: usize,
+    pub max_len: usize,
+    /// Characters allowed besides letters and digits.
+    pub extra_chars: String,
+    pub reserved: Vec<String>,
+    /// Whether a name taken by another client, ignoring case, gets a number
+    /// appended instead of being shared. Names the [`Authenticator`] proves
+    /// are refused instead, see [`Authenticator::proves_identity`].
+    ///
+    /// [`Authenticator`]: crate::Authenticator
+    /// [`Authenticator::proves_identity`]: crate::Authenticator::proves_identity
+    pub unique: bool,
+    /// Whether a logged in client may log in again under another name. The
+    /// members of its match are told with [`Packet::NameChanged`].
+    ///
+    /// [`Packet::NameChanged`]: network_types::connection::Packet::NameChanged
+    pub allow_rename: bool,
+}
+
+impl Default for NameRules {
+    fn default() -> Self {
+        Self {
+            min_len: 1,
+            max_len: 24,
+            extra_chars: "_-.".to_owned(),
+            reserved: vec!["server".to_owned(), "admin".to_owned(), "system".to_owned()],
+            unique: true,
+            allow_rename: true,
+        }
+    }
+}
+
+/// Why a name was refused.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NameError {
+    TooShort(usize),
+    TooLong(usize),
+    Forbidden(char),
+    Reserved,
+}
+
+impl fmt::Display for NameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NameError::TooShort(min) => write!(f, "must be at least {} characters long", min),
+            NameError::TooLong(max) => write!(f, "must be at most {} characters long", max),
+            NameError::Forbidden(c) => write!(f, "must not contain {:?}", c),
+            NameError::Reserved => write!(f, "is reserved"),
+        }
+    }
+}
+
+impl NameRules {
+    /// Returns `name` trimmed, if it follows the rules.
+    pub fn check<'a>(&self, name: &'a str) -> Result<&'a str, NameError> {
+        let name = name.trim();
+        let len = name.chars().count();
+        if len < self.min_len {
+            return Err(NameError::TooShort(self.min_len));
+        }
+        if len > self.max_len {
+            return Err(NameError::TooLong(self.max_len));
+        }
+        if let Some(c) = name
+            .chars()
+            .find(|c| !c.is_alphanumeric() && !self.extra_chars.contains(*c))
+        {
+            return Err(NameError::Forbidden(c));
+        }
+        if self
+            .reserved
+            .iter()
+            .any(|reserved| reserved.to_lowercase() == name.to_lowercase())
+        {
+            return Err(NameError::Reserved);
+        }
+        Ok(name)
+    }
+
+    /// The `attempt`th alternative to a taken `name`: `name2`, `name3` and
+    /// so on, cutting `name` short to stay within `max_len`.
+    pub(crate) fn suffixed(&self, name: &str, attempt: usize) -> String {
+        let suffix = (attempt + 1).to_string();
+        let keep = self.max_len.saturating_sub(suffix.len()).max(1);
+        name.chars().take(keep).chain(suffix.chars()).collect()
+    }
+}
+
+/// Key names are compared by, so that `Alice` and `alice` are the same name.
+pub(crate) fn fold(name: &str) -> String {
+    name.to_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_trims_and_accepts_allowed_names() {
+        let rules = NameRules::default();
+        assert_eq!(rules.check("  fred_99 "), Ok("fred_99"));
+        assert_eq!(rules.check("Zoë.b-2"), Ok("Zoë.b-2"));
+    }
+
+    #[test]
+    fn check_counts_characters_not_bytes() {
+        let rules = NameRules {
+            min_len: 3,
+            max_len: 4,
+            ..NameRules::default()
+        };
+        assert_eq!(rules.check(" ab "), Err(NameError::TooShort(3)));
+        assert_eq!(rules.check("éééé"), Ok("éééé"));
+        assert_eq!(rules.check("ééééé"), Err(NameError::TooLong(4)));
+    }
+
+    #[test]
+    fn check_refuses_forbidden_characters() {
+        let rules = NameRules::default();
+        assert_eq!(rules.check("fred flint"), Err(NameError::Forbidden(' ')));
+        assert_eq!(rules.check("fred!"), Err(NameError::Forbidden('!')));
+    }
+
+    #[test]
+    fn check_refuses_reserved_names_ignoring_case() {
+        let rules = NameRules::default();
+        assert_eq!(rules.check("Admin"), Err(NameError::Reserved));
+        assert_eq!(rules.check(" SERVER "), Err(NameError::Reserved));
+        assert_eq!(rules.check("admins"), Ok("admins"));
+    }
+
+    #[test]
+    fn suffixed_appends_the_attempt_number() {
+        let rules = NameRules::default();
+        assert_eq!(rules.suffixed("fred", 1), "fred2");
+        assert_eq!(rules.suffixed("fred", 9), "fred10");
+    }
+
+    #[test]
+    fn suffixed_stays_within_max_len() {
+        let rules = NameRules {
+            max_len: 5,
+            ..NameRules::default()
+        };
+        assert_eq!(rules.suffixed("wilma", 1), "wilm2");
+        assert_eq!(rules.suffixed("wilma", 99), "wi100");
+        assert_eq!(rules.suffixed("ééééé", 10), "ééé11");
+        assert_eq!(rules.suffixed("wil", 1), "wil2");
+    }
+}
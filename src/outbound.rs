@@ -0,0 +1,339 @@
+use std::{
+    collections::VecDeque,
+    io::Write,
+    net::{Shutdown, TcpStream},
+    str::FromStr,
+    sync::{Arc, Condvar, Mutex, OnceLock},
+    thread::{self, JoinHandle},
+};
+
+use log::info;
+use network_types::connection::{DisconnectReason, Packet};
+
+use crate::event_loop::Notifier;
+
+/// What to do when a client's outbound queue is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OverflowPolicy {
+    /// Drop the oldest queued frame.
+    DropOldest,
+    /// Drop the oldest relayed gameplay frame, disconnecting the client when
+    /// the queue holds nothing but lobby traffic.
+    #[default]
+    DropLowPriority,
+    /// Disconnect the client.
+    Disconnect,
+}
+
+impl FromStr for OverflowPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "drop_oldest" => Ok(OverflowPolicy::DropOldest),
+            "drop_low_priority" => Ok(OverflowPolicy::DropLowPriority),
+            "disconnect" => Ok(OverflowPolicy::Disconnect),
+            _ => Err("expected drop_oldest, drop_low_priority or disconnect".to_owned()),
+        }
+    }
+}
+
+/// Relayed gameplay traffic is `Low`, everything the server says itself is
+/// `Normal`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Priority {
+    Low,
+    Normal,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct OutboundConfig {
+    /// Frames a client may have waiting before `overflow` applies.
+    pub capacity: usize,
+    pub overflow: OverflowPolicy,
+}
+
+impl Default for OutboundConfig {
+    fn default() -> Self {
+        Self {
+            capacity: 256,
+            overflow: OverflowPolicy::default(),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct State {
+    frames: VecDeque<(Priority, Vec<u8>)>,
+    closed: bool,
+}
+
+#[derive(Debug)]
+struct Queue {
+    state: Mutex<State>,
+    ready: Condvar,
+    /// Set when the queue is drained by an event loop instead of a writer.
+    notifier: OnceLock<Notifier>,
+    config: OutboundConfig,
+}
+
+/// What [`Outbound::try_pop`] found in the queue.
+#[derive(Debug)]
+pub enum Next {
+    Frame(Vec<u8>),
+    Empty,
+    /// Closed and fully drained, the connection can be shut down.
+    Closed,
+}
+
+/// Bounded queue of frames for one client, drained by its own writer thread
+/// so that a slow client never blocks whoever is sending to it.
+#[derive(Debug, Clone)]
+pub struct Outbound {
+    queue: Arc<Queue>,
+}
+
+impl Outbound {
+    pub fn new(config: OutboundConfig) -> Self {
+        Self {
+            queue: Arc::new(Queue {
+                state: Mutex::new(State {
+                    frames: VecDeque::new(),
+                    closed: false,
+                }),
+                ready: Condvar::new(),
+                notifier: OnceLock::new(),
+                config,
+            }),
+        }
+    }
+
+    pub fn send(&self, packet: &Packet) {
+        self.push(Priority::Normal, packet.serialize_with_header());
+    }
+
+    /// Queues an already serialized packet relayed from another client.
+    pub fn relay(&self, message: &[u8]) {
+        let len = message.len() as u32;
+        let mut buf = Vec::with_capacity(4 + message.len());
+
+        buf.extend_from_slice(&len.to_be_bytes());
+        buf.extend_from_slice(message);
+
+        self.push(Priority::Low, buf);
+    }
+
+    /// Queues `packet` as the last frame; the connection is shut down once it
+    /// has been written.
+    pub fn close_with(&self, packet: &Packet) {
+        let mut state = self.queue.state.lock().unwrap();
+        if !state.closed {
+            state
+                .frames
+                .push_back((Priority::Normal, packet.serialize_with_header()));
+            state.closed = true;
+            self.wake();
+        }
+    }
+
+    /// Stops accepting frames; the connection is shut down once the queued
+    /// ones have been written.
+    pub fn close(&self) {
+        self.queue.state.lock().unwrap().closed = true;
+        self.wake();
+    }
+
+    /// Hands the queue over to an event loop, which is told about every
+    /// change through `notifier`.
+    pub fn set_notifier(&self, notifier: Notifier) {
+        let _ = self.queue.notifier.set(notifier);
+    }
+
+    /// Has whoever drains the queue look at the connection again, even
+    /// though nothing new was queued.
+    pub fn notify(&self) {
+        self.wake();
+    }
+
+    fn wake(&self) {
+        self.queue.ready.notify_all();
+        if let Some(notifier) = self.queue.notifier.get() {
+            notifier.notify();
+        }
+    }
+
+    fn push(&self, priority: Priority, frame: Vec<u8>) {
+        let mut state = self.queue.state.lock().unwrap();
+        if state.closed {
+            return;
+        }
+
+        if state.frames.len() >= self.queue.config.capacity {
+            let made_room = match self.queue.config.overflow {
+                OverflowPolicy::DropOldest => state.frames.pop_front().is_some(),
+                OverflowPolicy::DropLowPriority => {
+                    match state.frames.iter().position(|(p, _)| *p == Priority::Low) {
+                        Some(pos) => state.frames.remove(pos).is_some(),
+                        // Rather lose the new gameplay frame than the lobby ones
+                        None if priority == Priority::Low => return,
+                        None => false,
+                    }
+                }
+                OverflowPolicy::Disconnect => false,
+            };
+
+            if !made_room {
+                state.frames.clear();
+                state.frames.push_back((
+                    Priority::Normal,
+                    Packet::Disconnect {
+                        reason: DisconnectReason::SlowConsumer,
+                        message: "Outbound queue is full".to_owned(),
+                    }
+                    .serialize_with_header(),
+                ));
+                state.closed = true;
+                self.wake();
+                return;
+            }
+        }
+
+        state.frames.push_back((priority, frame));
+        self.wake();
+    }
+
+    /// Takes the next frame without waiting for one.
+    pub fn try_pop(&self) -> Next {
+        let mut state = self.queue.state.lock().unwrap();
+        match state.frames.pop_front() {
+            Some((_, frame)) => Next::Frame(frame),
+            None if state.closed => Next::Closed,
+            None => Next::Empty,
+        }
+    }
+
+    fn pop(&self) -> Option<Vec<u8>> {
+        let mut state = self.queue.state.lock().unwrap();
+        loop {
+            if let Some((_, frame)) = state.frames.pop_front() {
+                return Some(frame);
+            }
+            if state.closed {
+                return None;
+            }
+            state = self.queue.ready.wait(state).unwrap();
+        }
+    }
+
+    /// Spawns the writer draining this queue into `stream`. The socket is
+    /// shut down once the queue is closed and empty, or a write fails.
+    pub fn spawn_writer(&self, mut stream: TcpStream) -> JoinHandle<()> {
+        let outbound = self.clone();
+        thread::spawn(move || {
+            while let Some(frame) = outbound.pop() {
+                if let Err(err) = stream.write_all(frame.as_slice()) {
+                    info!("Client {:?} not available: {:?}", stream.peer_addr(), err);
+                    outbound.close();
+                    break;
+                }
+            }
+            let _ = stream.shutdown(Shutdown::Both);
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn queue(overflow: OverflowPolicy) -> Outbound {
+        Outbound::new(OutboundConfig {
+            capacity: 2,
+            overflow,
+        })
+    }
+
+    fn leave(room_id: i32) -> Packet {
+        Packet::LeaveMatch { room_id }
+    }
+
+    /// What the queue holds, as the room ids of the LeaveMatch packets and
+    /// 0 for a disconnect, and whether it is closed.
+    fn drain(outbound: &Outbound) -> (Vec<i32>, bool) {
+        let mut queued = Vec::new();
+        loop {
+            match outbound.try_pop() {
+                Next::Frame(frame) => match Packet::try_from(&frame[4..]).unwrap() {
+                    Packet::LeaveMatch { room_id } => queued.push(room_id),
+                    Packet::Disconnect {
+                        reason: DisconnectReason::SlowConsumer,
+                        ..
+                    } => queued.push(0),
+                    other => panic!("unexpected {:?}", other),
+                },
+                Next::Empty => return (queued, false),
+                Next::Closed => return (queued, true),
+            }
+        }
+    }
+
+    #[test]
+    fn drop_oldest_makes_room_for_any_frame() {
+        let outbound = queue(OverflowPolicy::DropOldest);
+        outbound.send(&leave(1));
+        outbound.relay(&leave(2).serialize());
+        outbound.send(&leave(3));
+        outbound.relay(&leave(4).serialize());
+        assert_eq!(drain(&outbound), (vec![3, 4], false));
+    }
+
+    #[test]
+    fn drop_low_priority_drops_relayed_frames_first() {
+        let outbound = queue(OverflowPolicy::DropLowPriority);
+        outbound.relay(&leave(1).serialize());
+        outbound.send(&leave(2));
+        outbound.send(&leave(3));
+        // Nothing relayed left to drop, so the new relayed frame goes
+        outbound.relay(&leave(4).serialize());
+        assert_eq!(drain(&outbound), (vec![2, 3], false));
+    }
+
+    #[test]
+    fn drop_low_priority_disconnects_when_lobby_traffic_overflows() {
+        let outbound = queue(OverflowPolicy::DropLowPriority);
+        outbound.send(&leave(1));
+        outbound.send(&leave(2));
+        outbound.send(&leave(3));
+        outbound.send(&leave(4));
+        assert_eq!(drain(&outbound), (vec![0], true));
+    }
+
+    #[test]
+    fn disconnect_drops_the_client_on_overflow() {
+        let outbound = queue(OverflowPolicy::Disconnect);
+        outbound.relay(&leave(1).serialize());
+        outbound.relay(&leave(2).serialize());
+        outbound.relay(&leave(3).serialize());
+        assert_eq!(drain(&outbound), (vec![0], true));
+    }
+
+    #[test]
+    fn close_with_sends_the_last_frame() {
+        let outbound = queue(OverflowPolicy::DropOldest);
+        outbound.send(&leave(1));
+        outbound.close_with(&leave(2));
+        outbound.send(&leave(3));
+        assert_eq!(drain(&outbound), (vec![1, 2], true));
+    }
+
+    #[test]
+    fn overflow_policies_parse() {
+        assert_eq!("drop_oldest".parse(), Ok(OverflowPolicy::DropOldest));
+        assert_eq!(
+            "drop_low_priority".parse(),
+            Ok(OverflowPolicy::DropLowPriority)
+        );
+        assert_eq!("disconnect".parse(), Ok(OverflowPolicy::Disconnect));
+        assert!("drop_newest".parse::<OverflowPolicy>().is_err());
+    }
+}
@@ -0,0 +1,491 @@
+use log::debug;
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    net::IpAddr,
+    sync::{Arc, RwLock},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    NameRules, client::Client, helpers, names::fold, room::RoomHandle, session::ClientState,
+    udp::Relay,
+};
+
+/// Every connected client keyed by id, with lookups by match and by name,
+/// ignoring case, kept in step with the records. A client's `match_id` and
+/// `name` must only be changed through [`ClientRegistry::set_match`] and
+/// [`ClientRegistry::set_name`], which also keeps its relay token valid for
+/// exactly as long as it is in a match.
+#[derive(Default)]
+pub struct ClientRegistry {
+    clients: HashMap<i32, Client>,
+    by_match: HashMap<i32, HashSet<i32>>,
+    by_name: HashMap<String, HashSet<i32>>,
+    relay: Arc<RwLock<Relay>>,
+}
+
+impl ClientRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Addresses the UDP relay forwards between, shared with it.
+    pub fn relay(&self) -> Arc<RwLock<Relay>> {
+        Arc::clone(&self.relay)
+    }
+
+    pub fn insert(&mut self, client: Client) {
+        let id = client.id;
+        if client.match_id != -1 {
+            self.by_match.entry(client.match_id).or_default().insert(id);
+        }
+        self.by_name
+            .entry(fold(&client.name))
+            .or_default()
+            .insert(id);
+        if let Some(old) = self.clients.insert(id, client) {
+            self.unindex(&old);
+        }
+    }
+
+    pub fn remove(&mut self, id: i32) -> Option<Client> {
+        let client = self.clients.remove(&id)?;
+        self.unindex(&client);
+        Some(client)
+    }
+
+    fn unindex(&mut self, client: &Client) {
+        if let Some(token) = client.relay_token {
+            self.relay.write().unwrap().revoke(token);
+        }
+        if let Some(members) = self.by_match.get_mut(&client.match_id) {
+            members.remove(&client.id);
+            if members.is_empty() {
+                self.by_match.remove(&client.match_id);
+            }
+        }
+        let key = fold(&client.name);
+        if let Some(named) = self.by_name.get_mut(&key) {
+            named.remove(&client.id);
+            if named.is_empty() {
+                self.by_name.remove(&key);
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.clients.len()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Client> {
+        self.clients.values()
+    }
+
+    pub fn get(&self, id: i32) -> Option<&Client> {
+        self.clients.get(&id)
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut Client> {
+        self.clients.values_mut()
+    }
+
+    /// Takes every client out of the registry.
+    pub fn drain(&mut self) -> impl Iterator<Item = Client> {
+        self.by_match.clear();
+        self.by_name.clear();
+        *self.relay.write().unwrap() = Relay::new();
+        std::mem::take(&mut self.clients).into_values()
+    }
+
+    /// Clients currently in match `match_id`.
+    pub fn members(&self, match_id: i32) -> impl Iterator<Item = &Client> {
+        self.by_match
+            .get(&match_id)
+            .into_iter()
+            .flatten()
+            .filter_map(|id| self.clients.get(id))
+    }
+
+    /// Clients called `name`, ignoring case.
+    pub fn named(&self, name: &str) -> impl Iterator<Item = &Client> {
+        self.by_name
+            .get(&fold(name))
+            .into_iter()
+            .flatten()
+            .filter_map(|id| self.clients.get(id))
+    }
+
+    /// Names `id` after `name`, or with [`NameRules::unique`] after the first
+    /// of its numbered variants no other client uses. A `proven` name is
+    /// never numbered, `None` is returned when another client uses it.
+    /// Returns the name given.
+    pub fn claim_name(
+        &mut self,
+        id: i32,
+        name: &str,
+        rules: &NameRules,
+        proven: bool,
+    ) -> Option<String> {
+        let mut claimed = name.to_owned();
+        if rules.unique {
+            let mut attempt = 1;
+            while self.named(&claimed).any(|client| client.id != id) {
+                if proven {
+                    return None;
+                }
+                claimed = rules.suffixed(name, attempt);
+                attempt += 1;
+            }
+        }
+        self.set_name(id, claimed.clone());
+        Some(claimed)
+    }
+
+    pub fn set_name(&mut self, id: i32, name: String) -> bool {
+        let Some(client) = self.clients.get(&id) else {
+            return false;
+        };
+        let key = fold(&client.name);
+        if let Some(named) = self.by_name.get_mut(&key) {
+            named.remove(&id);
+            if named.is_empty() {
+                self.by_name.remove(&key);
+            }
+        }
+        self.by_name.entry(fold(&name)).or_default().insert(id);
+        self.clients.get_mut(&id).unwrap().name = name;
+        true
+    }
+
+    pub fn set_state(&mut self, id: i32, state: ClientState) -> bool {
+        let Some(client) = self.clients.get_mut(&id) else {
+            return false;
+        };
+        debug!("Client ({}) {:?} -> {:?}", id, client.state, state);
+        client.state = state;
+        true
+    }
+
+    /// Moves a client in or out of a match (`-1` for none) together with the
+    /// matching session state. A client moving to another match is issued a
+    /// new relay token and its old one is revoked.
+    pub fn set_match(&mut self, id: i32, match_id: i32, state: ClientState) -> bool {
+        let Some(client) = self.clients.get_mut(&id) else {
+            return false;
+        };
+        debug!("Client ({}) {:?} -> {:?}", id, client.state, state);
+        let previous = client.match_id;
+        client.match_id = match_id;
+        client.state = state;
+
+        if previous != match_id {
+            let mut relay = self.relay.write().unwrap();
+            if let Some(token) = client.relay_token.take() {
+                relay.revoke(token);
+            }
+            if match_id != -1 {
+                client.relay_token = Some(relay.issue(match_id));
+            }
+        }
+
+        if let Some(members) = self.by_match.get_mut(&previous) {
+            members.remove(&id);
+            if members.is_empty() {
+                self.by_match.remove(&previous);
+            }
+        }
+        if match_id != -1 {
+            self.by_match.entry(match_id).or_default().insert(id);
+        }
+        true
+    }
+
+    /// Moves `id` into match `match_id` like [`ClientRegistry::set_match`],
+    /// but only from [`ClientState::Menu`] outside of any match. Requests
+    /// are checked against the state the client was in when they were read,
+    /// so two sent back to back could otherwise both be applied. Returns the
+    /// relay token issued to the client, or the state it is in instead if it
+    /// is still connected.
+    pub fn enter_match(
+        &mut self,
+        id: i32,
+        match_id: i32,
+        state: ClientState,
+    ) -> Result<u64, Option<ClientState>> {
+        match self.clients.get(&id) {
+            Some(client) if client.state == ClientState::Menu && client.match_id == -1 => {
+                self.set_match(id, match_id, state);
+                Ok(self.clients[&id].relay_token.unwrap())
+            }
+            client => Err(client.map(|c| c.state)),
+        }
+    }
+
+    /// Counts a wrong match password `id` tried, returning how many it has
+    /// tried so far.
+    pub fn record_password_failure(&mut self, id: i32) -> u32 {
+        let Some(client) = self.clients.get_mut(&id) else {
+            return 0;
+        };
+        client.password_failures += 1;
+        client.password_failures
+    }
+
+    /// Turns the pending heartbeat of `id` into a latency sample.
+    pub fn record_pong(&mut self, id: i32) {
+        if let Some(client) = self.clients.get_mut(&id)
+            && let Some(sent) = client.ping_sent.take()
+        {
+            client.latency = Some(sent.elapsed());
+        }
+    }
+}
+
+/// Characters invite codes are made of, leaving out look-alikes such as `0`
+/// and `O` or `1` and `I`.
+const CODE_ALPHABET: &[u8] = b"ABCDEFGHJKMNPQRSTUVWXYZ23456789";
+const CODE_LEN: usize = 6;
+
+/// Turns an invite code as typed by a player into the form it is stored in.
+pub fn normalize_code(code: &str) -> String {
+    code.chars()
+        .filter(|c| !c.is_whitespace() && *c != '-')
+        .map(|c| c.to_ascii_uppercase())
+        .collect()
+}
+
+/// Invite codes that do not exist an address may try within
+/// [`CODE_FAILURE_WINDOW`]. With two hundred matches open among the 887
+/// million codes, an address has about one chance in a million per window of
+/// hitting one. This does not hold back somebody guessing from many
+/// addresses.
+const MAX_CODE_FAILURES: u32 = 5;
+const CODE_FAILURE_WINDOW: Duration = Duration::from_secs(10 * 60);
+
+/// Invite codes that do not exist tried from each address. Counting by
+/// address rather than by connection keeps reconnecting from starting over.
+#[derive(Debug, Default)]
+pub struct CodeFailures {
+    /// Codes tried and when the first of them was.
+    by_ip: HashMap<IpAddr, (u32, Instant)>,
+}
+
+impl CodeFailures {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Whether `ip` may not try any more codes for now.
+    pub fn exhausted(&self, ip: IpAddr, now: Instant) -> bool {
+        self.by_ip.get(&ip).is_some_and(|(failures, since)| {
+            *failures >= MAX_CODE_FAILURES && now.duration_since(*since) < CODE_FAILURE_WINDOW
+        })
+    }
+
+    /// Counts a code `ip` tried that does not exist, forgetting addresses
+    /// whose window has passed.
+    pub fn record(&mut self, ip: IpAddr, now: Instant) {
+        self.by_ip
+            .retain(|_, (_, since)| now.duration_since(*since) < CODE_FAILURE_WINDOW);
+        self.by_ip.entry(ip).or_insert((0, now)).0 += 1;
+    }
+}
+
+/// Every running match keyed by id, listed in creation order, with a lookup
+/// by invite code. Only the dispatcher touches it. A match's `code` must only
+/// be changed through [`MatchRegistry::regenerate_code`].
+#[derive(Debug, Default)]
+pub struct MatchRegistry {
+    matches: BTreeMap<i32, RoomHandle>,
+    by_code: HashMap<String, i32>,
+    id_serial: i32,
+}
+
+impl MatchRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Id for the next match.
+    pub fn next_id(&mut self) -> i32 {
+        self.id_serial += 1;
+        self.id_serial
+    }
+
+    /// An invite code no running match uses.
+    pub fn unused_code(&self) -> String {
+        loop {
+            let mut bits = helpers::secret_u64();
+            let code = (0..CODE_LEN)
+                .map(|_| {
+                    let c = CODE_ALPHABET[(bits % CODE_ALPHABET.len() as u64) as usize];
+                    bits /= CODE_ALPHABET.len() as u64;
+                    c as char
+                })
+                .collect::<String>();
+            if !self.by_code.contains_key(&code) {
+                return code;
+            }
+        }
+    }
+
+    pub fn insert(&mut self, room: RoomHandle) {
+        self.by_code.insert(room.code.clone(), room.id);
+        if let Some(old) = self.matches.insert(room.id, room) {
+            self.by_code.remove(&old.code);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.matches.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.matches.is_empty()
+    }
+
+    pub fn get(&self, id: i32) -> Option<&RoomHandle> {
+        self.matches.get(&id)
+    }
+
+    pub fn get_mut(&mut self, id: i32) -> Option<&mut RoomHandle> {
+        self.matches.get_mut(&id)
+    }
+
+    /// The match using invite code `code`, see [`normalize_code`].
+    pub fn by_code(&self, code: &str) -> Option<&RoomHandle> {
+        self.by_code.get(code).and_then(|id| self.matches.get(id))
+    }
+
+    /// Gives match `id` a fresh invite code, retiring the old one.
+    pub fn regenerate_code(&mut self, id: i32) -> Option<String> {
+        let code = self.unused_code();
+        let room = self.matches.get_mut(&id)?;
+        self.by_code.remove(&room.code);
+        self.by_code.insert(code.clone(), id);
+        room.code = code.clone();
+        Some(code)
+    }
+
+    pub fn remove(&mut self, id: i32) -> Option<RoomHandle> {
+        let room = self.matches.remove(&id)?;
+        self.by_code.remove(&room.code);
+        Some(room)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &RoomHandle> {
+        self.matches.values()
+    }
+
+    /// Takes every match out of the registry.
+    pub fn drain(&mut self) -> impl Iterator<Item = RoomHandle> {
+        self.by_code.clear();
+        std::mem::take(&mut self.matches).into_values()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::tests::connected;
+
+    fn registry(clients: i32) -> ClientRegistry {
+        let mut registry = ClientRegistry::new();
+        for id in 1..=clients {
+            registry.insert(connected(id));
+        }
+        registry
+    }
+
+    #[test]
+    fn claim_name_numbers_names_in_use() {
+        let rules = NameRules::default();
+        let mut clients = registry(3);
+        assert_eq!(
+            clients.claim_name(1, "Fred", &rules, false),
+            Some("Fred".to_owned())
+        );
+        assert_eq!(
+            clients.claim_name(2, "fred", &rules, false),
+            Some("fred2".to_owned())
+        );
+        assert_eq!(
+            clients.claim_name(3, "FRED", &rules, false),
+            Some("FRED3".to_owned())
+        );
+        // Claiming its own name again changes nothing
+        assert_eq!(
+            clients.claim_name(1, "fred", &rules, false),
+            Some("fred".to_owned())
+        );
+    }
+
+    #[test]
+    fn claim_name_refuses_proven_names_in_use() {
+        let rules = NameRules::default();
+        let mut clients = registry(2);
+        assert_eq!(
+            clients.claim_name(1, "fred", &rules, true),
+            Some("fred".to_owned())
+        );
+        assert_eq!(clients.claim_name(2, "Fred", &rules, true), None);
+        assert_eq!(clients.get(2).unwrap().name, "");
+        assert_eq!(
+            clients.claim_name(1, "fred", &rules, true),
+            Some("fred".to_owned())
+        );
+    }
+
+    #[test]
+    fn claim_name_shares_names_unless_unique() {
+        let rules = NameRules {
+            unique: false,
+            ..NameRules::default()
+        };
+        let mut clients = registry(2);
+        assert_eq!(
+            clients.claim_name(1, "fred", &rules, true),
+            Some("fred".to_owned())
+        );
+        assert_eq!(
+            clients.claim_name(2, "fred", &rules, true),
+            Some("fred".to_owned())
+        );
+        assert_eq!(clients.named("FRED").count(), 2);
+    }
+
+    #[test]
+    fn normalize_code_ignores_case_spaces_and_dashes() {
+        assert_eq!(normalize_code("ABC234"), "ABC234");
+        assert_eq!(normalize_code("abc234"), "ABC234");
+        assert_eq!(normalize_code(" abc-234 "), "ABC234");
+        assert_eq!(normalize_code("a b\tc-2-3 4"), "ABC234");
+        assert_eq!(normalize_code(" - "), "");
+    }
+
+    #[test]
+    fn code_failures_count_per_address_for_a_while() {
+        let (fred, barney) = (IpAddr::from([10, 0, 0, 1]), IpAddr::from([10, 0, 0, 2]));
+        let start = Instant::now();
+        let mut failures = CodeFailures::new();
+        for _ in 0..MAX_CODE_FAILURES {
+            assert!(!failures.exhausted(fred, start));
+            failures.record(fred, start);
+        }
+        assert!(failures.exhausted(fred, start));
+        assert!(!failures.exhausted(barney, start));
+
+        let later = start + CODE_FAILURE_WINDOW;
+        assert!(!failures.exhausted(fred, later));
+        failures.record(barney, later);
+        assert_eq!(failures.by_ip.len(), 1, "expired addresses are forgotten");
+    }
+
+    #[test]
+    fn unused_code_is_already_normalized() {
+        let code = MatchRegistry::new().unused_code();
+        assert_eq!(code.len(), CODE_LEN);
+        assert!(code.bytes().all(|c| CODE_ALPHABET.contains(&c)));
+        assert_eq!(normalize_code(&code), code);
+    }
+}
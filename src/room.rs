@@ -0,0 +1,943 @@
+use std::{
+    str::FromStr,
+    sync::{Arc, RwLock},
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use crossbeam::channel::{Receiver, Sender, TrySendError, bounded};
+use log::{debug, info, warn};
+use network_types::connection::{ErrorCode, MatchOptions, Packet, RequestKind, Visibility};
+
+use crate::{
+    ServerConfig,
+    client::{enter_match, send_ack, send_error, set_client_match, set_client_state},
+    dispatcher::{Dispatcher, Message},
+    helpers,
+    outbound::Outbound,
+    registry::ClientRegistry,
+    session::ClientState,
+};
+
+/// Most members any match takes, whatever the server allows. A match's queue
+/// has room for a notice from every member, see [`RoomHandle`].
+pub(crate) const MAX_PLAYERS: u32 = 1024;
+
+/// Wrong passwords a client may try before it may not join any match that
+/// has one for the rest of its connection.
+const MAX_PASSWORD_FAILURES: u32 = 5;
+
+/// Who takes over a match when its owner leaves or drops.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum HostMigration {
+    /// The member that has been in the match the longest.
+    #[default]
+    OldestMember,
+    /// The member with the lowest measured round trip time, falling back to
+    /// the oldest member when nobody has been measured yet.
+    LowestLatency,
+    /// Delete the match instead.
+    DeleteRoom,
+}
+
+impl FromStr for HostMigration {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "oldest_member" => Ok(HostMigration::OldestMember),
+            "lowest_latency" => Ok(HostMigration::LowestLatency),
+            "delete_room" => Ok(HostMigration::DeleteRoom),
+            _ => Err("expected oldest_member, lowest_latency or delete_room".to_owned()),
+        }
+    }
+}
+
+/// How every match on the server behaves.
+#[derive(Debug, Clone, Copy)]
+pub struct RoomSettings {
+    pub host_migration: HostMigration,
+    /// Whether clients may join a match that has already started.
+    pub late_join: bool,
+    /// Most members a match may take, and takes unless its owner asks for
+    /// fewer.
+    pub max_players: u32,
+    /// Time between the host starting a match and its members beginning to
+    /// play, for everybody to load the map.
+    pub start_delay: Duration,
+    /// Requests a match's thread holds before rejecting more.
+    pub queue_limit: usize,
+}
+
+impl From<&ServerConfig> for RoomSettings {
+    fn from(config: &ServerConfig) -> Self {
+        Self {
+            host_migration: config.host_migration,
+            late_join: config.late_join,
+            max_players: config.max_players,
+            start_delay: config.match_start_delay,
+            queue_limit: config.queue_limits.room,
+        }
+    }
+}
+
+/// Lobby requests the dispatcher routes to the match they are about.
+#[derive(Debug)]
+pub enum RoomCommand {
+    Join {
+        id: i32,
+        password: Option<String>,
+        request_id: Option<u32>,
+    },
+    Leave {
+        id: i32,
+        request_id: Option<u32>,
+    },
+    /// The client is gone and its record has already been removed.
+    Disconnected {
+        id: i32,
+        name: String,
+    },
+    Delete {
+        id: i32,
+        request_id: Option<u32>,
+    },
+    Start {
+        id: i32,
+        map: String,
+        request_id: Option<u32>,
+    },
+    SpawnPlayers {
+        id: i32,
+        positions: Vec<(f32, f32, f32)>,
+        request_id: Option<u32>,
+    },
+}
+
+impl RoomCommand {
+    /// The client and request a command answers, `None` for notices.
+    fn request(&self) -> Option<(i32, Option<u32>, RequestKind)> {
+        match *self {
+            RoomCommand::Join { id, request_id, .. } => {
+                Some((id, request_id, RequestKind::JoinMatch))
+            }
+            RoomCommand::Leave { id, request_id } => {
+                Some((id, request_id, RequestKind::LeaveMatch))
+            }
+            RoomCommand::Delete { id, request_id } => {
+                Some((id, request_id, RequestKind::DeleteMatch))
+            }
+            RoomCommand::Start { id, request_id, .. } => {
+                Some((id, request_id, RequestKind::StartMatch))
+            }
+            RoomCommand::SpawnPlayers { id, request_id, .. } => {
+                Some((id, request_id, RequestKind::SpawnPlayers))
+            }
+            RoomCommand::Disconnected { .. } => None,
+        }
+    }
+
+    /// Answers a command for a match that does not exist (anymore).
+    pub fn reject(self, room_id: i32, clients: &Arc<RwLock<ClientRegistry>>) {
+        let Some((id, request_id, request)) = self.request() else {
+            return;
+        };
+        send_error(
+            id,
+            request_id,
+            clients,
+            request,
+            ErrorCode::RoomNotFound,
+            format!("Match {} does not exist", room_id),
+        );
+    }
+}
+
+/// The dispatcher's side of a running match: what the match list shows and
+/// the channel to the match's thread.
+#[derive(Debug)]
+pub struct RoomHandle {
+    pub id: i32,
+    pub name: String,
+    /// Current owner, kept up to date through [`Message::RoomChanged`].
+    pub owner_id: i32,
+    /// Invite code, see [`Packet::JoinByCode`].
+    pub code: String,
+    pub players: i32,
+    pub max_players: i32,
+    pub visibility: Visibility,
+    /// Whether joining takes a password.
+    pub locked: bool,
+    pub tags: Vec<String>,
+    /// Set through [`Message::RoomStarted`].
+    pub started: bool,
+    pub map: Option<String>,
+    /// Holds `queue_limit` requests and a notice for every member on top,
+    /// only ever sent to by the dispatcher.
+    commands: Sender<RoomCommand>,
+    queue_limit: usize,
+    thread: JoinHandle<()>,
+}
+
+impl RoomHandle {
+    /// Hands `command` to the match without waiting, rejecting it when the
+    /// match has stopped. Requests finding `queue_limit` others waiting are
+    /// answered with [`ErrorCode::ServerBusy`], notices always fit.
+    pub fn send(&self, command: RoomCommand, clients: &Arc<RwLock<ClientRegistry>>) {
+        if self.commands.len() >= self.queue_limit
+            && let Some((id, request_id, request)) = command.request()
+        {
+            send_error(
+                id,
+                request_id,
+                clients,
+                request,
+                ErrorCode::ServerBusy,
+                format!("Match {} is busy, try again later", self.id),
+            );
+            return;
+        }
+        match self.commands.try_send(command) {
+            Ok(()) => {}
+            Err(TrySendError::Disconnected(command)) => command.reject(self.id, clients),
+            // Only members are sent notices, and never more of them than fit
+            Err(TrySendError::Full(command)) => {
+                warn!("Match {} queue overflow, dropped {:?}", self.id, command);
+            }
+        }
+    }
+
+    /// Waits for the match's thread, which stops once the handle's channel
+    /// is dropped.
+    pub fn join(self) {
+        drop(self.commands);
+        let _ = self.thread.join();
+    }
+}
+
+/// A match, run by its own thread. It owns its members and their sockets,
+/// and only hears from the rest of the server through [`RoomCommand`]s.
+#[derive(Debug)]
+pub struct Match {
+    id: i32,
+    owner_id: i32,
+    name: String,
+    clients: Vec<i32>,
+    clients_sockets: Vec<Outbound>,
+    /// As asked for by the owner, with `max_players` always set.
+    options: MatchOptions,
+    started: Option<Started>,
+    closed: bool,
+}
+
+/// What the members of a started match were told to play, repeated to late
+/// joiners.
+#[derive(Debug)]
+struct Started {
+    map: String,
+    seed: u64,
+    start_at: u64,
+}
+
+impl Started {
+    fn packet(&self, room_id: i32) -> Packet {
+        Packet::MatchStarted {
+            room_id,
+            map: self.map.clone(),
+            seed: self.seed,
+            start_at: self.start_at,
+        }
+    }
+}
+
+impl Match {
+    pub fn new(
+        id: i32,
+        owner_id: i32,
+        name: String,
+        options: MatchOptions,
+        owner: Outbound,
+    ) -> Self {
+        Self {
+            id,
+            owner_id,
+            name,
+            clients: vec![owner_id],
+            clients_sockets: vec![owner],
+            options,
+            started: None,
+            closed: false,
+        }
+    }
+
+    /// Starts the match's thread. The dispatcher hears back through
+    /// [`Message::RoomChanged`], [`Message::RoomStarted`] and
+    /// [`Message::RoomClosed`].
+    pub fn spawn(
+        self,
+        code: String,
+        clients: Arc<RwLock<ClientRegistry>>,
+        dispatcher: Dispatcher,
+        settings: RoomSettings,
+    ) -> RoomHandle {
+        let queue_limit = settings.queue_limit;
+        let (commands, rx) = bounded(queue_limit + self.max_players());
+        let id = self.id;
+        let name = self.name.clone();
+        let owner_id = self.owner_id;
+        let players = self.clients.len() as i32;
+        let max_players = self.max_players() as i32;
+        let visibility = self.options.visibility;
+        let locked = self.options.password.is_some();
+        let tags = self.options.tags.clone();
+        let thread = thread::spawn(move || self.run(rx, clients, dispatcher, settings));
+        RoomHandle {
+            id,
+            name,
+            owner_id,
+            code,
+            players,
+            max_players,
+            visibility,
+            locked,
+            tags,
+            started: false,
+            map: None,
+            commands,
+            queue_limit,
+            thread,
+        }
+    }
+
+    fn run(
+        mut self,
+        commands: Receiver<RoomCommand>,
+        clients: Arc<RwLock<ClientRegistry>>,
+        dispatcher: Dispatcher,
+        settings: RoomSettings,
+    ) {
+        let host_migration = settings.host_migration;
+        // Runs until the dispatcher drops the handle, which it does once told
+        // that the match closed. Whatever arrives in between is rejected.
+        for command in commands.iter() {
+            if self.closed {
+                command.reject(self.id, &clients);
+                continue;
+            }
+
+            let players = self.clients.len();
+            let was_started = self.started.is_some();
+            let mut joined = None;
+            match command {
+                RoomCommand::Join {
+                    id,
+                    password,
+                    request_id,
+                } => {
+                    if self.join(id, password, request_id, &clients, settings.late_join) {
+                        joined = Some(id);
+                    }
+                }
+                RoomCommand::Leave { id, request_id } => {
+                    let Some(name) = clients.read().unwrap().get(id).map(|c| c.name.clone()) else {
+                        continue;
+                    };
+                    if !self.depart(id, &name, &clients, host_migration) {
+                        send_error(
+                            id,
+                            request_id,
+                            &clients,
+                            RequestKind::LeaveMatch,
+                            ErrorCode::NotInRoom,
+                            format!("Not in match {}", self.id),
+                        );
+                        continue;
+                    }
+                    set_client_match(id, -1, ClientState::Menu, &clients);
+                    send_ack(id, request_id, &clients);
+                }
+                RoomCommand::Disconnected { id, name } => {
+                    self.depart(id, &name, &clients, host_migration);
+                }
+                RoomCommand::Delete { id, request_id } => {
+                    if self.owner_id != id {
+                        send_error(
+                            id,
+                            request_id,
+                            &clients,
+                            RequestKind::DeleteMatch,
+                            ErrorCode::NotHost,
+                            format!("Only the owner can delete match {}", self.id),
+                        );
+                        continue;
+                    }
+                    self.close(id, &clients);
+                    send_ack(id, request_id, &clients);
+                }
+                RoomCommand::Start {
+                    id,
+                    map,
+                    request_id,
+                } => self.start(id, map, request_id, &clients, settings.start_delay),
+                RoomCommand::SpawnPlayers {
+                    id,
+                    positions,
+                    request_id,
+                } => self.spawn_players(id, positions, request_id, &clients),
+            }
+
+            if let Some(started) = self.started.as_ref().filter(|_| !was_started) {
+                dispatcher.send(Message::RoomStarted {
+                    room_id: self.id,
+                    map: started.map.clone(),
+                });
+            }
+            if self.closed {
+                dispatcher.send(Message::RoomClosed { room_id: self.id });
+            } else if self.clients.len() != players {
+                dispatcher.send(Message::RoomChanged {
+                    room_id: self.id,
+                    players: self.clients.len() as i32,
+                    owner_id: self.owner_id,
+                    joined,
+                });
+            }
+        }
+    }
+
+    fn max_players(&self) -> usize {
+        self.options
+            .max_players
+            .unwrap_or(MAX_PLAYERS)
+            .min(MAX_PLAYERS) as usize
+    }
+
+    /// Adds `id` to the members and introduces it to everybody. A started
+    /// match only takes new members with `late_join`, who go straight into
+    /// the game. Returns false when it could not join.
+    fn join(
+        &mut self,
+        id: i32,
+        password: Option<String>,
+        request_id: Option<u32>,
+        clients: &Arc<RwLock<ClientRegistry>>,
+        late_join: bool,
+    ) -> bool {
+        if self.started.is_some() && !late_join {
+            send_error(
+                id,
+                request_id,
+                clients,
+                RequestKind::JoinMatch,
+                ErrorCode::RoomStarted,
+                format!("Match {} has already started", self.id),
+            );
+            return false;
+        }
+        if self.clients.contains(&id) {
+            send_error(
+                id,
+                request_id,
+                clients,
+                RequestKind::JoinMatch,
+                ErrorCode::AlreadyInRoom,
+                format!("Already in match {}", self.id),
+            );
+            return false;
+        }
+        let exhausted = clients
+            .read()
+            .unwrap()
+            .get(id)
+            .is_some_and(|c| c.password_failures >= MAX_PASSWORD_FAILURES);
+        if self.options.password.is_some() && exhausted {
+            send_error(
+                id,
+                request_id,
+                clients,
+                RequestKind::JoinMatch,
+                ErrorCode::TooManyAttempts,
+                "Too many wrong passwords".to_owned(),
+            );
+            return false;
+        }
+        if self.options.password.is_some() && self.options.password != password {
+            clients.write().unwrap().record_password_failure(id);
+            send_error(
+                id,
+                request_id,
+                clients,
+                RequestKind::JoinMatch,
+                ErrorCode::WrongPassword,
+                format!("Wrong password for match {}", self.id),
+            );
+            return false;
+        }
+        if self.clients.len() >= self.max_players() {
+            send_error(
+                id,
+                request_id,
+                clients,
+                RequestKind::JoinMatch,
+                ErrorCode::RoomFull,
+                format!("Match {} is full", self.id),
+            );
+            return false;
+        }
+
+        let state = match self.started {
+            Some(_) => ClientState::InGame,
+            None => ClientState::MatchClient,
+        };
+        let Some(relay_token) = enter_match(
+            id,
+            self.id,
+            state,
+            RequestKind::JoinMatch,
+            request_id,
+            clients,
+        ) else {
+            return false;
+        };
+
+        let clients = clients.read().unwrap();
+
+        let Some((joined_client_id, joined_client_name, joined_client_outbound)) = clients
+            .get(id)
+            .map(|client| (client.id, client.name.clone(), client.outbound.clone()))
+        else {
+            return false;
+        };
+
+        self.clients.push(id);
+        self.clients_sockets.push(joined_client_outbound.clone());
+
+        for client_id in self.clients.iter() {
+            // Notify client that he joined successfully
+            let Some(client) = clients.get(*client_id) else {
+                continue;
+            };
+
+            debug!("Sending MatchJoined for {}", *client_id);
+
+            // Tell new Client about the other clients, the entry about
+            // itself comes last and answers the request
+            joined_client_outbound.send(
+                &Packet::MatchJoined {
+                    id: self.id,
+                    user_id: client.id,
+                    user_name: client.name.clone(),
+                    room_name: self.name.clone(),
+                }
+                .reply_to(if client.id == id { request_id } else { None }),
+            );
+
+            if client.id != id {
+                // Tell room clients about the new client
+                client.outbound.send(&Packet::MatchJoined {
+                    id: self.id,
+                    user_id: joined_client_id,
+                    user_name: joined_client_name.clone(),
+                    room_name: self.name.clone(),
+                });
+            }
+        }
+        joined_client_outbound.send(&Packet::RelayToken {
+            room_id: self.id,
+            token: relay_token,
+        });
+        if let Some(started) = &self.started {
+            joined_client_outbound.send(&started.packet(self.id));
+        }
+        true
+    }
+
+    /// Tells every member but `id` that the match was deleted and sends all
+    /// of them back to the menu.
+    fn close(&mut self, id: i32, clients: &Arc<RwLock<ClientRegistry>>) {
+        info!("Deleted Match {} owned by {}", self.id, self.owner_id);
+        for (client_id, outbound) in self.clients.iter().zip(self.clients_sockets.iter()) {
+            if *client_id != id {
+                outbound.send(&Packet::MatchDeleted);
+            }
+        }
+        for client_id in self.clients.iter() {
+            set_client_match(*client_id, -1, ClientState::Menu, clients);
+        }
+        self.closed = true;
+    }
+
+    /// Takes `id` out of the match and tells the remaining members. When the
+    /// owner leaves, the match is handed over according to `host_migration`.
+    /// The match closes when nobody is left in it. Returns false when `id`
+    /// was not a member.
+    fn depart(
+        &mut self,
+        id: i32,
+        name: &str,
+        clients: &Arc<RwLock<ClientRegistry>>,
+        host_migration: HostMigration,
+    ) -> bool {
+        // The owner is always a member
+        if self.owner_id == id && host_migration == HostMigration::DeleteRoom {
+            self.close(id, clients);
+            return true;
+        }
+
+        // Sockets are kept in the same order as ids
+        let Some(member) = self.clients.iter().position(|c| *c == id) else {
+            return false;
+        };
+        self.clients.remove(member);
+        self.clients_sockets.remove(member);
+
+        // Tell Everybody
+        let leaved = Packet::MatchLeaved {
+            user_id: id,
+            user_name: name.to_owned(),
+        };
+        self.clients_sockets
+            .iter()
+            .for_each(|outbound| outbound.send(&leaved));
+
+        if self.clients.is_empty() {
+            info!("Deleted empty Match {}", self.id);
+            self.closed = true;
+            return true;
+        }
+
+        if self.owner_id == id {
+            let new_owner_id = match host_migration {
+                HostMigration::LowestLatency => {
+                    let clients = clients.read().unwrap();
+                    self.clients
+                        .iter()
+                        .filter_map(|member| {
+                            clients
+                                .get(*member)
+                                .and_then(|c| c.latency)
+                                .map(|latency| (latency, *member))
+                        })
+                        .min()
+                        .map(|(_, member)| member)
+                        .unwrap_or(self.clients[0])
+                }
+                _ => self.clients[0],
+            };
+            info!("Match {} host {} -> {}", self.id, id, new_owner_id);
+            self.owner_id = new_owner_id;
+
+            let host_changed = Packet::HostChanged {
+                room_id: self.id,
+                new_owner_id,
+            };
+            self.clients_sockets
+                .iter()
+                .for_each(|outbound| outbound.send(&host_changed));
+            // In game the host is only told apart by owner_id
+            if self.started.is_none() {
+                set_client_state(new_owner_id, ClientState::MatchHost, clients);
+            }
+        }
+        true
+    }
+
+    /// Moves every member into the game and tells them which map to load,
+    /// with a seed and start time they all share.
+    fn start(
+        &mut self,
+        id: i32,
+        map: String,
+        request_id: Option<u32>,
+        clients: &Arc<RwLock<ClientRegistry>>,
+        delay: Duration,
+    ) {
+        if self.owner_id != id {
+            send_error(
+                id,
+                request_id,
+                clients,
+                RequestKind::StartMatch,
+                ErrorCode::NotHost,
+                format!("Only the owner can start match {}", self.id),
+            );
+            return;
+        }
+        if self.started.is_some() {
+            send_error(
+                id,
+                request_id,
+                clients,
+                RequestKind::StartMatch,
+                ErrorCode::RoomStarted,
+                format!("Match {} has already started", self.id),
+            );
+            return;
+        }
+        let started = Started {
+            map,
+            seed: helpers::random_u64(),
+            start_at: (helpers::unix_time() + delay).as_millis() as u64,
+        };
+        info!("Match {} started on {:?}", self.id, started.map);
+        for (client_id, outbound) in self.clients.iter().zip(self.clients_sockets.iter()) {
+            set_client_state(*client_id, ClientState::InGame, clients);
+            // The host's copy answers its request
+            let reply_to = if *client_id == id { request_id } else { None };
+            outbound.send(&started.packet(self.id).reply_to(reply_to));
+        }
+        self.started = Some(started);
+    }
+
+    fn spawn_players(
+        &self,
+        id: i32,
+        positions: Vec<(f32, f32, f32)>,
+        request_id: Option<u32>,
+        clients: &Arc<RwLock<ClientRegistry>>,
+    ) {
+        if self.owner_id != id {
+            send_error(
+                id,
+                request_id,
+                clients,
+                RequestKind::SpawnPlayers,
+                ErrorCode::NotHost,
+                format!("Only the owner can spawn players in {}", self.id),
+            );
+            return;
+        }
+
+        let names = clients.read().unwrap();
+        let mut index = 0;
+        for (client_id, outbound) in self.clients.iter().zip(self.clients_sockets.iter()) {
+            let spawn = positions[index];
+
+            if let Some(client) = names.get(*client_id) {
+                debug!("Spawn {:?} for {}", spawn, client.name);
+            }
+
+            outbound.send(&Packet::Spawn { position: spawn });
+            index += 1;
+            if index >= positions.len() {
+                index = 0;
+            }
+        }
+        drop(names);
+        send_ack(id, request_id, clients);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{client::tests::connected, outbound::Next};
+
+    /// Match 10 hosted by client 1, with clients 2 to `clients` in the menu.
+    fn hosted(options: MatchOptions, clients: i32) -> (Match, Arc<RwLock<ClientRegistry>>) {
+        let mut registry = ClientRegistry::new();
+        for id in 1..=clients {
+            registry.insert(connected(id));
+            registry.set_state(id, ClientState::Menu);
+        }
+        registry.set_match(1, 10, ClientState::MatchHost);
+        let owner = registry.get(1).unwrap().outbound.clone();
+        let room = Match::new(10, 1, "friday".to_owned(), options, owner);
+        (room, Arc::new(RwLock::new(registry)))
+    }
+
+    fn locked(password: &str) -> MatchOptions {
+        MatchOptions {
+            password: Some(password.to_owned()),
+            ..MatchOptions::default()
+        }
+    }
+
+    /// The code of the last error queued for `id`, if any.
+    fn refusal(id: i32, clients: &Arc<RwLock<ClientRegistry>>) -> Option<ErrorCode> {
+        let outbound = clients.read().unwrap().get(id).unwrap().outbound.clone();
+        let mut code = None;
+        while let Next::Frame(frame) = outbound.try_pop() {
+            if let Ok(Packet::Error { code: refused, .. }) = Packet::try_from(&frame[4..]) {
+                code = Some(refused);
+            }
+        }
+        code
+    }
+
+    fn state(id: i32, clients: &Arc<RwLock<ClientRegistry>>) -> ClientState {
+        clients.read().unwrap().get(id).unwrap().state
+    }
+
+    #[test]
+    fn members_cannot_join_again() {
+        let (mut room, clients) = hosted(MatchOptions::default(), 2);
+        assert!(!room.join(1, None, None, &clients, false));
+        assert_eq!(refusal(1, &clients), Some(ErrorCode::AlreadyInRoom));
+        assert_eq!(state(1, &clients), ClientState::MatchHost);
+        assert_eq!(room.clients, [1]);
+    }
+
+    #[test]
+    fn only_the_host_starts_the_match() {
+        let (mut room, clients) = hosted(MatchOptions::default(), 2);
+        assert!(room.join(2, None, None, &clients, false));
+        room.start(2, "quarry".to_owned(), None, &clients, Duration::ZERO);
+        assert_eq!(refusal(2, &clients), Some(ErrorCode::NotHost));
+        assert!(room.started.is_none());
+        assert_eq!(state(2, &clients), ClientState::MatchClient);
+    }
+
+    /// Match 10 hosted by client 1 and joined by 2 to `members`, in order.
+    fn joined(members: i32) -> (Match, Arc<RwLock<ClientRegistry>>) {
+        let (mut room, clients) = hosted(MatchOptions::default(), members);
+        for id in 2..=members {
+            assert!(room.join(id, None, None, &clients, false));
+        }
+        (room, clients)
+    }
+
+    fn measure(id: i32, millis: u64, clients: &Arc<RwLock<ClientRegistry>>) {
+        let mut clients = clients.write().unwrap();
+        let client = clients.iter_mut().find(|c| c.id == id).unwrap();
+        client.latency = Some(Duration::from_millis(millis));
+    }
+
+    #[test]
+    fn oldest_member_takes_over() {
+        let (mut room, clients) = joined(3);
+        measure(3, 10, &clients);
+        assert!(room.depart(1, "host", &clients, HostMigration::OldestMember));
+        assert_eq!(room.owner_id, 2);
+        assert_eq!(state(2, &clients), ClientState::MatchHost);
+        assert_eq!(state(3, &clients), ClientState::MatchClient);
+    }
+
+    #[test]
+    fn lowest_latency_takes_over() {
+        let (mut room, clients) = joined(4);
+        measure(2, 80, &clients);
+        measure(3, 20, &clients);
+        assert!(room.depart(1, "host", &clients, HostMigration::LowestLatency));
+        assert_eq!(room.owner_id, 3);
+        assert_eq!(state(3, &clients), ClientState::MatchHost);
+        assert_eq!(state(2, &clients), ClientState::MatchClient);
+    }
+
+    #[test]
+    fn lowest_latency_falls_back_to_the_oldest_member() {
+        let (mut room, clients) = joined(3);
+        assert!(room.depart(1, "host", &clients, HostMigration::LowestLatency));
+        assert_eq!(room.owner_id, 2);
+    }
+
+    #[test]
+    fn delete_room_closes_the_match_when_the_host_leaves() {
+        let (mut room, clients) = joined(3);
+        assert!(room.depart(2, "guest", &clients, HostMigration::DeleteRoom));
+        assert!(!room.closed, "members leave without closing the match");
+
+        assert!(room.depart(1, "host", &clients, HostMigration::DeleteRoom));
+        assert!(room.closed);
+        assert_eq!(state(3, &clients), ClientState::Menu);
+        assert_eq!(clients.read().unwrap().get(3).unwrap().match_id, -1);
+    }
+
+    /// The MatchStarted packets queued for `id`, as their maps.
+    fn starts(id: i32, clients: &Arc<RwLock<ClientRegistry>>) -> Vec<String> {
+        let outbound = clients.read().unwrap().get(id).unwrap().outbound.clone();
+        let mut maps = Vec::new();
+        while let Next::Frame(frame) = outbound.try_pop() {
+            if let Ok(Packet::MatchStarted { map, .. }) = Packet::try_from(&frame[4..]) {
+                maps.push(map);
+            }
+        }
+        maps
+    }
+
+    #[test]
+    fn start_moves_every_member_into_the_game() {
+        let (mut room, clients) = joined(3);
+        room.start(1, "quarry".to_owned(), None, &clients, Duration::ZERO);
+        for id in 1..=3 {
+            assert_eq!(starts(id, &clients), ["quarry"]);
+            assert_eq!(state(id, &clients), ClientState::InGame);
+        }
+    }
+
+    #[test]
+    fn started_matches_refuse_joins_without_late_join() {
+        let (mut room, clients) = hosted(MatchOptions::default(), 2);
+        room.start(1, "quarry".to_owned(), None, &clients, Duration::ZERO);
+        assert!(!room.join(2, None, None, &clients, false));
+        assert_eq!(refusal(2, &clients), Some(ErrorCode::RoomStarted));
+        assert_eq!(state(2, &clients), ClientState::Menu);
+    }
+
+    #[test]
+    fn late_joiners_go_straight_into_the_game() {
+        let (mut room, clients) = hosted(MatchOptions::default(), 2);
+        room.start(1, "quarry".to_owned(), None, &clients, Duration::ZERO);
+        assert!(room.join(2, None, None, &clients, true));
+        assert_eq!(starts(2, &clients), ["quarry"]);
+        assert_eq!(state(2, &clients), ClientState::InGame);
+    }
+
+    #[test]
+    fn max_players_never_exceeds_the_hard_limit() {
+        let (room, _) = hosted(MatchOptions::default(), 1);
+        assert_eq!(room.max_players(), MAX_PLAYERS as usize);
+        let options = MatchOptions {
+            max_players: Some(u32::MAX),
+            ..MatchOptions::default()
+        };
+        let (room, _) = hosted(options, 1);
+        assert_eq!(room.max_players(), MAX_PLAYERS as usize);
+    }
+
+    #[test]
+    fn join_takes_the_right_password_only() {
+        let (mut room, clients) = hosted(locked("hunter2"), 3);
+
+        assert!(!room.join(2, None, None, &clients, false));
+        assert_eq!(refusal(2, &clients), Some(ErrorCode::WrongPassword));
+        assert!(!room.join(2, Some("hunter3".to_owned()), None, &clients, false));
+        assert_eq!(refusal(2, &clients), Some(ErrorCode::WrongPassword));
+        assert_eq!(state(2, &clients), ClientState::Menu);
+
+        assert!(room.join(2, Some("hunter2".to_owned()), None, &clients, false));
+        assert_eq!(refusal(2, &clients), None);
+        assert_eq!(state(2, &clients), ClientState::MatchClient);
+        assert_eq!(room.clients, [1, 2]);
+    }
+
+    #[test]
+    fn wrong_passwords_run_out() {
+        let (mut room, clients) = hosted(locked("hunter2"), 3);
+
+        for _ in 0..MAX_PASSWORD_FAILURES {
+            assert!(!room.join(2, None, None, &clients, false));
+            assert_eq!(refusal(2, &clients), Some(ErrorCode::WrongPassword));
+        }
+        assert!(!room.join(2, Some("hunter2".to_owned()), None, &clients, false));
+        assert_eq!(refusal(2, &clients), Some(ErrorCode::TooManyAttempts));
+
+        // Others keep their own attempts
+        assert!(room.join(3, Some("hunter2".to_owned()), None, &clients, false));
+    }
+
+    #[test]
+    fn full_match_refuses_joins() {
+        let options = MatchOptions {
+            max_players: Some(2),
+            ..MatchOptions::default()
+        };
+        let (mut room, clients) = hosted(options, 3);
+
+        assert!(room.join(2, None, None, &clients, false));
+        assert!(!room.join(3, None, None, &clients, false));
+        assert_eq!(refusal(3, &clients), Some(ErrorCode::RoomFull));
+        assert_eq!(state(3, &clients), ClientState::Menu);
+        assert_eq!(room.clients, [1, 2]);
+    }
+}
@@ -0,0 +1,748 @@
+use std::{
+    fmt, io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream},
+    str::FromStr,
+    sync::{
+        Arc, RwLock,
+        atomic::{AtomicBool, Ordering},
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use crossbeam::channel::{Sender, bounded};
+use log::{info, warn};
+use mio::Waker;
+
+use crate::{
+    Anonymous, Authenticator, ConfigError, NameRules,
+    client::{Client, Heartbeat, heartbeat},
+    dispatcher::{Dispatcher, Message, dispatch},
+    event_loop::EventLoop,
+    listing::ListingSettings,
+    login::Logins,
+    metrics::Metrics,
+    outbound::OutboundConfig,
+    registry::ClientRegistry,
+    room::{HostMigration, MAX_PLAYERS, RoomSettings},
+    session::FrameLimits,
+    udp,
+};
+
+/// How client connections are served.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum IoMode {
+    /// Every socket is driven by a single readiness based event loop.
+    #[default]
+    EventLoop,
+    /// Every client gets its own reader and writer threads.
+    Blocking,
+}
+
+impl FromStr for IoMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "event_loop" => Ok(IoMode::EventLoop),
+            "blocking" => Ok(IoMode::Blocking),
+            _ => Err("expected event_loop or blocking".to_owned()),
+        }
+    }
+}
+
+/// How many messages the internal queues hold before overload handling
+/// kicks in.
+#[derive(Debug, Clone, Copy)]
+pub struct QueueLimits {
+    /// Lobby requests waiting for the dispatcher, more are rejected.
+    pub dispatcher: usize,
+    /// UDP datagrams waiting to be relayed, more are dropped.
+    pub udp_relay: usize,
+    /// Requests waiting for a match's thread, more are rejected.
+    pub room: usize,
+    /// Logins waiting for a login worker, more are rejected.
+    pub login: usize,
+}
+
+impl Default for QueueLimits {
+    fn default() -> Self {
+        Self {
+            dispatcher: 1024,
+            udp_relay: 4096,
+            room: 64,
+            login: 256,
+        }
+    }
+}
+
+/// Everything a [`Server`] can be tuned with.
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    /// Address the lobby listens on. Port `0` picks a free port, see
+    /// [`Server::local_addrs`].
+    pub tcp_addr: String,
+    /// Address the gameplay relay listens on.
+    pub udp_addr: String,
+    /// Threads relaying UDP datagrams.
+    pub udp_workers: usize,
+    /// Threads checking login credentials, which may take a while, see
+    /// [`Authenticator`].
+    pub login_workers: usize,
+    /// Largest UDP datagram accepted, longer ones are truncated.
+    pub udp_buffer_size: usize,
+    /// Connections served at once, more are turned away.
+    pub max_clients: usize,
+    /// Matches running at once, more are refused.
+    pub max_rooms: usize,
+    pub io_mode: IoMode,
+    pub frame_limits: FrameLimits,
+    pub queue_limits: QueueLimits,
+    pub outbound: OutboundConfig,
+    pub heartbeat: Heartbeat,
+    pub host_migration: HostMigration,
+    /// Most members a match may take, at most 1024.
+    pub max_players: u32,
+    /// Whether clients may join a match that has already started.
+    pub late_join: bool,
+    /// Time members of a starting match get to load the map.
+    pub match_start_delay: Duration,
+    pub names: NameRules,
+    /// Where the server runs, reported with every listed match.
+    pub region: String,
+    /// Most entries in one match list.
+    pub match_list_page_size: usize,
+    /// How often the queue figures are logged.
+    pub metrics_interval: Duration,
+    /// How long a shutdown waits for running matches to end before the
+    /// remaining clients are disconnected.
+    pub shutdown_grace: Duration,
+}
+
+impl ServerConfig {
+    /// Checks that the settings make sense, naming offending settings the
+    /// way the configuration file does.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let counts = [
+            ("udp_workers", self.udp_workers),
+            ("login_workers", self.login_workers),
+            ("max_clients", self.max_clients),
+            ("max_rooms", self.max_rooms),
+            ("max_players", self.max_players as usize),
+            ("frame_limit_before_login", self.frame_limits.before_login),
+            ("frame_limit_after_login", self.frame_limits.after_login),
+            ("dispatcher_queue", self.queue_limits.dispatcher),
+            ("udp_relay_queue", self.queue_limits.udp_relay),
+            ("room_queue", self.queue_limits.room),
+            ("login_queue", self.queue_limits.login),
+            ("outbound_queue", self.outbound.capacity),
+            ("name_min_len", self.names.min_len),
+            ("match_list_page_size", self.match_list_page_size),
+        ];
+        for (key, count) in counts {
+            if count == 0 {
+                return Err(ConfigError::invalid(key, "must be greater than 0"));
+            }
+        }
+        if self.max_players > MAX_PLAYERS {
+            return Err(ConfigError::invalid(
+                "max_players",
+                format!("must be at most {}", MAX_PLAYERS),
+            ));
+        }
+        if !(1..=MAX_DATAGRAM_SIZE).contains(&self.udp_buffer_size) {
+            return Err(ConfigError::invalid(
+                "udp_buffer_size",
+                format!("must be between 1 and {}", MAX_DATAGRAM_SIZE),
+            ));
+        }
+        if self.frame_limits.before_login > self.frame_limits.after_login {
+            return Err(ConfigError::invalid(
+                "frame_limit_before_login",
+                "must not exceed frame_limit_after_login",
+            ));
+        }
+        if self.names.max_len < self.names.min_len {
+            return Err(ConfigError::invalid(
+                "name_max_len",
+                "must not be less than name_min_len",
+            ));
+        }
+        if self.heartbeat.interval.is_zero() {
+            return Err(ConfigError::invalid(
+                "heartbeat_interval_ms",
+                "must be greater than 0",
+            ));
+        }
+        if self.heartbeat.timeout <= self.heartbeat.interval {
+            return Err(ConfigError::invalid(
+                "heartbeat_timeout_ms",
+                "must be longer than heartbeat_interval_ms",
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            tcp_addr: "0.0.0.0:7878".to_owned(),
+            udp_addr: "0.0.0.0:7879".to_owned(),
+            udp_workers: 4,
+            login_workers: 2,
+            udp_buffer_size: 2048,
+            max_clients: 4096,
+            max_rooms: 1024,
+            io_mode: IoMode::default(),
+            frame_limits: FrameLimits::default(),
+            queue_limits: QueueLimits::default(),
+            outbound: OutboundConfig::default(),
+            heartbeat: Heartbeat::default(),
+            host_migration: HostMigration::default(),
+            max_players: 64,
+            late_join: false,
+            match_start_delay: Duration::from_secs(3),
+            names: NameRules::default(),
+            region: String::new(),
+            match_list_page_size: 50,
+            metrics_interval: Duration::from_secs(30),
+            shutdown_grace: Duration::from_secs(30),
+        }
+    }
+}
+
+/// Largest payload a UDP datagram can carry.
+const MAX_DATAGRAM_SIZE: usize = 65507;
+
+type ConnectHook = Arc<dyn Fn(i32, SocketAddr) + Send + Sync>;
+type DisconnectHook = Arc<dyn Fn(i32) + Send + Sync>;
+type DrainedHook = Arc<dyn Fn() + Send + Sync>;
+
+/// Callbacks into the embedding application. They run on the server's own
+/// threads, so they should return quickly.
+#[derive(Clone, Default)]
+pub struct Hooks {
+    on_connect: Option<ConnectHook>,
+    on_disconnect: Option<DisconnectHook>,
+    on_drained: Option<DrainedHook>,
+}
+
+impl Hooks {
+    pub(crate) fn connected(&self, id: i32, peer_addr: SocketAddr) {
+        if let Some(hook) = &self.on_connect {
+            hook(id, peer_addr);
+        }
+    }
+
+    pub(crate) fn disconnected(&self, id: i32) {
+        if let Some(hook) = &self.on_disconnect {
+            hook(id);
+        }
+    }
+
+    pub(crate) fn drained(&self) {
+        if let Some(hook) = &self.on_drained {
+            hook();
+        }
+    }
+}
+
+impl fmt::Debug for Hooks {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Hooks")
+            .field("on_connect", &self.on_connect.is_some())
+            .field("on_disconnect", &self.on_disconnect.is_some())
+            .field("on_drained", &self.on_drained.is_some())
+            .finish()
+    }
+}
+
+/// Sets up and starts a [`Server`].
+///
+/// ```no_run
+/// let server = network_manager::ServerBuilder::new()
+///     .tcp_addr("127.0.0.1:0")
+///     .udp_addr("127.0.0.1:0")
+///     .start()?;
+/// println!("Lobby on {}", server.local_addrs().tcp);
+/// server.shutdown()?;
+/// # Ok::<(), std::io::Error>(())
+/// ```
+pub struct ServerBuilder {
+    config: ServerConfig,
+    hooks: Hooks,
+    authenticator: Arc<dyn Authenticator>,
+}
+
+impl Default for ServerBuilder {
+    fn default() -> Self {
+        Self {
+            config: ServerConfig::default(),
+            hooks: Hooks::default(),
+            authenticator: Arc::new(Anonymous),
+        }
+    }
+}
+
+impl ServerBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Replaces every setting at once.
+    pub fn config(mut self, config: ServerConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    pub fn tcp_addr(mut self, addr: impl Into<String>) -> Self {
+        self.config.tcp_addr = addr.into();
+        self
+    }
+
+    pub fn udp_addr(mut self, addr: impl Into<String>) -> Self {
+        self.config.udp_addr = addr.into();
+        self
+    }
+
+    pub fn udp_workers(mut self, workers: usize) -> Self {
+        self.config.udp_workers = workers;
+        self
+    }
+
+    pub fn login_workers(mut self, workers: usize) -> Self {
+        self.config.login_workers = workers;
+        self
+    }
+
+    pub fn udp_buffer_size(mut self, size: usize) -> Self {
+        self.config.udp_buffer_size = size;
+        self
+    }
+
+    pub fn max_clients(mut self, max_clients: usize) -> Self {
+        self.config.max_clients = max_clients;
+        self
+    }
+
+    pub fn max_rooms(mut self, max_rooms: usize) -> Self {
+        self.config.max_rooms = max_rooms;
+        self
+    }
+
+    pub fn io_mode(mut self, io_mode: IoMode) -> Self {
+        self.config.io_mode = io_mode;
+        self
+    }
+
+    pub fn frame_limits(mut self, frame_limits: FrameLimits) -> Self {
+        self.config.frame_limits = frame_limits;
+        self
+    }
+
+    pub fn queue_limits(mut self, queue_limits: QueueLimits) -> Self {
+        self.config.queue_limits = queue_limits;
+        self
+    }
+
+    pub fn outbound(mut self, outbound: OutboundConfig) -> Self {
+        self.config.outbound = outbound;
+        self
+    }
+
+    pub fn heartbeat(mut self, heartbeat: Heartbeat) -> Self {
+        self.config.heartbeat = heartbeat;
+        self
+    }
+
+    pub fn host_migration(mut self, host_migration: HostMigration) -> Self {
+        self.config.host_migration = host_migration;
+        self
+    }
+
+    pub fn max_players(mut self, max_players: u32) -> Self {
+        self.config.max_players = max_players;
+        self
+    }
+
+    pub fn late_join(mut self, late_join: bool) -> Self {
+        self.config.late_join = late_join;
+        self
+    }
+
+    pub fn match_start_delay(mut self, delay: Duration) -> Self {
+        self.config.match_start_delay = delay;
+        self
+    }
+
+    pub fn names(mut self, names: NameRules) -> Self {
+        self.config.names = names;
+        self
+    }
+
+    pub fn region(mut self, region: impl Into<String>) -> Self {
+        self.config.region = region.into();
+        self
+    }
+
+    pub fn match_list_page_size(mut self, page_size: usize) -> Self {
+        self.config.match_list_page_size = page_size;
+        self
+    }
+
+    pub fn shutdown_grace(mut self, grace: Duration) -> Self {
+        self.config.shutdown_grace = grace;
+        self
+    }
+
+    /// Decides who may log in, [`Anonymous`] by default.
+    pub fn authenticator(mut self, authenticator: Arc<dyn Authenticator>) -> Self {
+        self.authenticator = authenticator;
+        self
+    }
+
+    /// Called with the id and address of every accepted connection.
+    pub fn on_connect(mut self, hook: impl Fn(i32, SocketAddr) + Send + Sync + 'static) -> Self {
+        self.hooks.on_connect = Some(Arc::new(hook));
+        self
+    }
+
+    /// Called with the id of every client once it is gone.
+    pub fn on_disconnect(mut self, hook: impl Fn(i32) + Send + Sync + 'static) -> Self {
+        self.hooks.on_disconnect = Some(Arc::new(hook));
+        self
+    }
+
+    /// Called once the last match ended while draining, see
+    /// [`Server::set_draining`].
+    pub fn on_drained(mut self, hook: impl Fn() + Send + Sync + 'static) -> Self {
+        self.hooks.on_drained = Some(Arc::new(hook));
+        self
+    }
+
+    /// Validates the settings, binds both sockets and starts serving from
+    /// background threads.
+    pub fn start(self) -> io::Result<Server> {
+        let Self {
+            config,
+            hooks,
+            authenticator,
+        } = self;
+        config
+            .validate()
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+
+        let listener = TcpListener::bind(config.tcp_addr.as_str())?;
+        let tcp_addr = listener.local_addr()?;
+        info!("Server listening on {}", tcp_addr);
+
+        let accepting = Arc::new(AtomicBool::new(true));
+        let running = Arc::new(AtomicBool::new(true));
+        let clients: Arc<RwLock<ClientRegistry>> = Arc::new(RwLock::new(ClientRegistry::new()));
+
+        let metrics = Arc::new(Metrics::default());
+        let relay = clients.read().unwrap().relay();
+        let (udp_addr, udp) = udp::server(
+            &config.udp_addr,
+            relay,
+            running.clone(),
+            config.udp_workers,
+            config.udp_buffer_size,
+            config.queue_limits.udp_relay,
+            metrics.clone(),
+        )?;
+
+        let (tx, rx) = Dispatcher::new(config.queue_limits.dispatcher, metrics.clone());
+        let (logins, login_workers) = Logins::spawn(
+            config.login_workers,
+            config.queue_limits.login,
+            authenticator,
+            Arc::new(config.names.clone()),
+            clients.clone(),
+            tx.clone(),
+            metrics.clone(),
+        );
+
+        let (network, wake) = match config.io_mode {
+            IoMode::EventLoop => {
+                let event_loop = EventLoop::new(
+                    listener,
+                    tx.clone(),
+                    clients.clone(),
+                    &config,
+                    logins,
+                    hooks.clone(),
+                )?;
+                let waker = event_loop.waker();
+                let accepting = accepting.clone();
+                let running = running.clone();
+                (
+                    thread::spawn(move || event_loop.run(accepting, running)),
+                    Wake::EventLoop(waker),
+                )
+            }
+            IoMode::Blocking => {
+                let accepting = accepting.clone();
+                let tx = tx.clone();
+                let clients = clients.clone();
+                let config = config.clone();
+                let hooks = hooks.clone();
+                (
+                    thread::spawn(move || {
+                        accept(listener, accepting, tx, clients, config, logins, hooks)
+                    }),
+                    Wake::Blocking,
+                )
+            }
+        };
+
+        let main_loop = {
+            let clients = clients.clone();
+            let tx = tx.clone();
+            let rooms = RoomSettings::from(&config);
+            let listing = ListingSettings::from(&config);
+            let max_rooms = config.max_rooms;
+            thread::spawn(move || dispatch(rx, clients, tx, rooms, listing, max_rooms, hooks))
+        };
+
+        // Background threads stop once `stop` is dropped
+        let (stop, stopped) = bounded::<()>(0);
+        let heartbeat_loop = {
+            let clients = clients.clone();
+            let interval = config.heartbeat.interval;
+            let stopped = stopped.clone();
+            thread::spawn(move || heartbeat(clients, interval, stopped))
+        };
+        let metrics_loop = metrics.spawn_reporter(config.metrics_interval, stopped);
+
+        Ok(Server {
+            local_addrs: LocalAddrs {
+                tcp: tcp_addr,
+                udp: udp_addr,
+            },
+            accepting,
+            running,
+            grace: config.shutdown_grace,
+            wake,
+            network: Some(network),
+            udp,
+            stop: Some(stop),
+            background: vec![heartbeat_loop, metrics_loop],
+            login_workers,
+            dispatcher: tx,
+            main_loop: Some(main_loop),
+            clients,
+        })
+    }
+}
+
+/// Addresses a [`Server`] actually listens on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LocalAddrs {
+    pub tcp: SocketAddr,
+    pub udp: SocketAddr,
+}
+
+/// How to get the network thread to notice that the server is stopping.
+enum Wake {
+    EventLoop(Arc<Waker>),
+    /// The blocking accept loop only wakes up for a new connection.
+    Blocking,
+}
+
+/// A running server, built by [`ServerBuilder`]. Dropping it shuts it down.
+pub struct Server {
+    local_addrs: LocalAddrs,
+    /// Cleared first, so that no new connections come in while the server
+    /// winds down.
+    accepting: Arc<AtomicBool>,
+    /// Cleared once the matches are over, stops everything else.
+    running: Arc<AtomicBool>,
+    grace: Duration,
+    wake: Wake,
+    network: Option<JoinHandle<io::Result<()>>>,
+    udp: Vec<JoinHandle<()>>,
+    stop: Option<Sender<()>>,
+    background: Vec<JoinHandle<()>>,
+    /// Stop once the last session is gone.
+    login_workers: Vec<JoinHandle<()>>,
+    dispatcher: Dispatcher,
+    main_loop: Option<JoinHandle<()>>,
+    clients: Arc<RwLock<ClientRegistry>>,
+}
+
+impl Server {
+    pub fn local_addrs(&self) -> LocalAddrs {
+        self.local_addrs
+    }
+
+    /// Puts the server in drain mode, or takes it out. While draining, new
+    /// matches are refused, running ones go on, and clients are told through
+    /// [`Packet::MatchList`](network_types::connection::Packet::MatchList).
+    /// The `on_drained` hook runs once no match is left.
+    pub fn set_draining(&self, draining: bool) {
+        self.dispatcher.send(Message::SetDraining { draining });
+    }
+
+    /// Blocks until the server stops serving connections, which only happens
+    /// when the network thread fails, then shuts down the rest.
+    pub fn wait(mut self) -> io::Result<()> {
+        let result = self.join_network();
+        self.stop()?;
+        result
+    }
+
+    /// Stops accepting connections and waits up to the configured grace
+    /// period for running matches to end. Then every client is sent a
+    /// [`Packet::Disconnect`](network_types::connection::Packet::Disconnect)
+    /// and every thread of the server is joined.
+    pub fn shutdown(mut self) -> io::Result<()> {
+        self.stop()
+    }
+
+    fn join_network(&mut self) -> io::Result<()> {
+        match self.network.take() {
+            Some(network) => network.join().unwrap_or(Ok(())),
+            None => Ok(()),
+        }
+    }
+
+    fn stop(&mut self) -> io::Result<()> {
+        if self.accepting.swap(false, Ordering::Relaxed) && self.network.is_some() {
+            match &self.wake {
+                Wake::EventLoop(waker) => {
+                    let _ = waker.wake();
+                }
+                Wake::Blocking => {
+                    let _ = TcpStream::connect(reachable(self.local_addrs.tcp));
+                }
+            }
+        }
+
+        // Lets the matches play out, then the dispatcher hangs up on everyone
+        if let Some(main_loop) = self.main_loop.take() {
+            self.dispatcher
+                .send(Message::Shutdown { grace: self.grace });
+            let _ = main_loop.join();
+        }
+
+        self.running.store(false, Ordering::Relaxed);
+        if let (Wake::EventLoop(waker), Some(_)) = (&self.wake, &self.network) {
+            let _ = waker.wake();
+        }
+        let result = self.join_network();
+
+        for handle in self.udp.drain(..) {
+            let _ = handle.join();
+        }
+
+        self.stop.take();
+        for handle in self.background.drain(..) {
+            let _ = handle.join();
+        }
+
+        // Reader threads take the lock too, it must not be held while joining
+        let remaining: Vec<Client> = self.clients.write().unwrap().drain().collect();
+        for mut client in remaining {
+            client.join();
+        }
+        for handle in self.login_workers.drain(..) {
+            let _ = handle.join();
+        }
+
+        result
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.stop();
+    }
+}
+
+/// Where to connect to reach a socket bound to `addr`.
+fn reachable(addr: SocketAddr) -> SocketAddr {
+    match addr.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => (Ipv4Addr::LOCALHOST, addr.port()).into(),
+        IpAddr::V6(ip) if ip.is_unspecified() => (Ipv6Addr::LOCALHOST, addr.port()).into(),
+        _ => addr,
+    }
+}
+
+/// Accepts connections and gives each client its own threads, used with
+/// [`IoMode::Blocking`].
+fn accept(
+    listener: TcpListener,
+    accepting: Arc<AtomicBool>,
+    tx: Dispatcher,
+    clients: Arc<RwLock<ClientRegistry>>,
+    config: ServerConfig,
+    logins: Logins,
+    hooks: Hooks,
+) -> io::Result<()> {
+    let names = Arc::new(config.names.clone());
+    let mut client_id_serial: i32 = 0;
+    for stream in listener.incoming() {
+        if !accepting.load(Ordering::Relaxed) {
+            break;
+        }
+        match stream.and_then(|stream| Ok((stream.peer_addr()?, stream))) {
+            Ok((peer_addr, stream)) => {
+                if clients.read().unwrap().len() >= config.max_clients {
+                    Client::refuse(stream, peer_addr);
+                    continue;
+                }
+                info!("Client {} connected", peer_addr);
+
+                // The client is registered before its reader looks it up
+                let mut registry = clients.write().unwrap();
+                let client = Client::new(client_id_serial, peer_addr, stream, config.outbound)
+                    .start(
+                        tx.clone(),
+                        clients.clone(),
+                        config.frame_limits,
+                        config.heartbeat,
+                        logins.clone(),
+                        names.clone(),
+                    );
+                match client {
+                    Ok(client) => registry.insert(client),
+                    Err(err) => {
+                        warn!("Client {} refused: {}", peer_addr, err);
+                        continue;
+                    }
+                }
+                drop(registry);
+                hooks.connected(client_id_serial, peer_addr);
+
+                client_id_serial += 1;
+            }
+            Err(e) => warn!("Connection failed: {}", e),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validate_bounds_max_players() {
+        let config = |max_players| ServerConfig {
+            max_players,
+            ..ServerConfig::default()
+        };
+        assert!(config(1).validate().is_ok());
+        assert!(config(MAX_PLAYERS).validate().is_ok());
+        assert!(matches!(
+            config(0).validate(),
+            Err(ConfigError::Invalid { key, .. }) if key == "max_players"
+        ));
+        assert!(matches!(
+            config(MAX_PLAYERS + 1).validate(),
+            Err(ConfigError::Invalid { key, .. }) if key == "max_players"
+        ));
+    }
+}
@@ -0,0 +1,705 @@
+use std::{
+    io::ErrorKind,
+    net::SocketAddr,
+    sync::{Arc, RwLock},
+};
+
+use log::{debug, info, trace};
+use network_types::connection::{
+    DecodeError, DisconnectReason, ErrorCode, PROTOCOL_VERSION, Packet, RequestKind,
+};
+
+use crate::{
+    NameRules,
+    client::{Client, Heartbeat, send_error, set_client_state},
+    dispatcher::{Dispatcher, Message},
+    helpers::ReadError,
+    login::{Login, LoginState, Logins},
+    outbound::Outbound,
+    registry::ClientRegistry,
+};
+
+/// Oldest [`Packet`] layout revision this server still understands.
+const MIN_PROTOCOL_VERSION: u16 = PROTOCOL_VERSION;
+
+/// Optional protocol features this server can negotiate during the handshake.
+const SERVER_CAPABILITIES: &[&str] = &[];
+
+/// Where a client is in its session. Owned by the server-side [`Client`]
+/// record: the reader thread only leaves `Handshake` and `Guest`, every
+/// other transition is made by the main loop once a request has succeeded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ClientState {
+    Handshake,
+    /// Past the handshake but not logged in, may only look around.
+    Guest,
+    Menu,
+    MatchClient,
+    MatchHost,
+    InGame,
+}
+
+impl ClientState {
+    /// Whether a client in this state may send `packet`.
+    pub fn allows(&self, packet: &Packet) -> bool {
+        let relayed = matches!(
+            packet,
+            Packet::SpawnRemoteObject { .. }
+                | Packet::DespawnRemoteObject { .. }
+                | Packet::RemoteObjectCall { .. }
+                | Packet::RemoteObjectLocation { .. }
+                | Packet::Message { .. }
+        );
+        match self {
+            ClientState::Handshake => matches!(packet, Packet::Hello { .. }),
+            ClientState::Guest => matches!(
+                packet,
+                Packet::Ping
+                    | Packet::LoginRequest { .. }
+                    | Packet::ListMatches { .. }
+                    | Packet::RemoveFromListMatches
+            ),
+            ClientState::Menu => matches!(
+                packet,
+                Packet::Ping
+                    | Packet::LoginRequest { .. }
+                    | Packet::ListMatches { .. }
+                    | Packet::RemoveFromListMatches
+                    | Packet::JoinMatch { .. }
+                    | Packet::JoinByCode { .. }
+                    | Packet::NewMatch { .. }
+            ),
+            // Logging in again renames the client, if the rules allow it
+            ClientState::MatchClient => {
+                relayed
+                    || matches!(
+                        packet,
+                        Packet::Ping | Packet::LoginRequest { .. } | Packet::LeaveMatch { .. }
+                    )
+            }
+            ClientState::MatchHost => {
+                relayed
+                    || matches!(
+                        packet,
+                        Packet::Ping
+                            | Packet::LoginRequest { .. }
+                            | Packet::LeaveMatch { .. }
+                            | Packet::StartMatch { .. }
+                            | Packet::DeleteMatch { .. }
+                            | Packet::SpawnPlayers { .. }
+                            | Packet::RegenerateJoinCode { .. }
+                    )
+            }
+            // Host-only requests are checked against the match owner
+            ClientState::InGame => {
+                relayed
+                    || matches!(
+                        packet,
+                        Packet::Ping
+                            | Packet::LoginRequest { .. }
+                            | Packet::LeaveMatch { .. }
+                            | Packet::DeleteMatch { .. }
+                            | Packet::SpawnPlayers { .. }
+                            | Packet::RegenerateJoinCode { .. }
+                    )
+            }
+        }
+    }
+}
+
+/// Largest frame payload accepted from a client, depending on how far into
+/// the session it is.
+#[derive(Debug, Clone, Copy)]
+pub struct FrameLimits {
+    pub before_login: usize,
+    pub after_login: usize,
+}
+
+impl Default for FrameLimits {
+    fn default() -> Self {
+        Self {
+            before_login: 1024,
+            after_login: 64 * 1024,
+        }
+    }
+}
+
+impl FrameLimits {
+    pub fn max_for(&self, logged_in: bool) -> usize {
+        if logged_in {
+            self.after_login
+        } else {
+            self.before_login
+        }
+    }
+}
+
+/// What the code reading a client's socket keeps between frames.
+pub(crate) struct Session {
+    pub id: i32,
+    pub peer_addr: SocketAddr,
+    pub outbound: Outbound,
+    logins: Logins,
+    names: Arc<NameRules>,
+    login: Arc<LoginState>,
+}
+
+impl Session {
+    pub fn new(
+        id: i32,
+        peer_addr: SocketAddr,
+        outbound: &Outbound,
+        logins: Logins,
+        names: Arc<NameRules>,
+    ) -> Self {
+        Self {
+            id,
+            peer_addr,
+            outbound: outbound.clone(),
+            logins,
+            names,
+            login: Arc::default(),
+        }
+    }
+
+    pub fn logged_in(&self) -> bool {
+        self.login.logged_in()
+    }
+
+    /// Whether a login is being checked, see [`Logins`]. No further frame
+    /// may be handled until it is done.
+    pub fn login_pending(&self) -> bool {
+        self.login.pending()
+    }
+
+    /// Blocks until no login is being checked.
+    pub fn wait_for_login(&self) {
+        self.login.wait();
+    }
+
+    fn reject(&self, tx: &Dispatcher, reason: String) {
+        info!("Client ({}) rejected: {}", self.id, reason);
+        self.outbound.close_with(&Packet::HelloRejected {
+            protocol_version: PROTOCOL_VERSION,
+            reason,
+        });
+        tx.disconnected(self.id);
+    }
+
+    fn disconnect(&self, tx: &Dispatcher, reason: DisconnectReason, message: String) {
+        info!("Client ({}) disconnected by server: {}", self.id, message);
+        self.outbound
+            .close_with(&Packet::Disconnect { reason, message });
+        tx.disconnected(self.id);
+    }
+
+    fn protocol_violation(&self, tx: &Dispatcher, state: ClientState, err: DecodeError) {
+        let message = format!("Invalid packet: {}", err);
+        if let ClientState::Handshake = state {
+            self.reject(tx, message);
+            return;
+        }
+
+        let reason = match err {
+            DecodeError::Oversized { .. } => DisconnectReason::FrameTooLarge,
+            _ => DisconnectReason::ProtocolViolation,
+        };
+        self.disconnect(tx, reason, message);
+    }
+
+    /// Handles a failed read on the session's socket. Timeouts and malformed
+    /// frames are answered before the client is dropped.
+    pub fn read_failed(
+        &self,
+        err: ReadError,
+        tx: &Dispatcher,
+        clients: &Arc<RwLock<ClientRegistry>>,
+        heartbeat: Heartbeat,
+    ) {
+        let id = self.id;
+        match err {
+            ReadError::Decode(err) => {
+                let state = Client::state_of(id, clients).unwrap_or(ClientState::Handshake);
+                self.protocol_violation(tx, state, err);
+            }
+            ReadError::Io(err)
+                if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) =>
+            {
+                self.disconnect(
+                    tx,
+                    DisconnectReason::Timeout,
+                    format!("Nothing received for {:?}", heartbeat.timeout),
+                );
+            }
+            ReadError::Io(err) => {
+                tx.disconnected(id);
+                info!("Client disconnected {:?}: {}", self.peer_addr, err);
+            }
+        }
+    }
+
+    /// Handles one frame read from the session's socket, `packet` decoded
+    /// from the payload `buffer`. Returns false once nothing more should be
+    /// read from it.
+    pub fn handle_frame(
+        &mut self,
+        packet: Packet,
+        buffer: Vec<u8>,
+        tx: &Dispatcher,
+        clients: &Arc<RwLock<ClientRegistry>>,
+    ) -> bool {
+        let id = self.id;
+        let outbound = &self.outbound;
+        let state = match Client::state_of(id, clients) {
+            Some(state) => state,
+            None => return false,
+        };
+
+        // Unwrap correlated requests, relaying only the inner packet
+        let (request_id, packet, buffer) = match packet {
+            Packet::Request { request_id, packet } => {
+                if let Packet::Request { .. } | Packet::Response { .. } = *packet {
+                    self.protocol_violation(
+                        tx,
+                        state,
+                        DecodeError::Malformed("nested request".to_owned()),
+                    );
+                    return false;
+                }
+                let buffer = packet.serialize();
+                (Some(request_id), *packet, buffer)
+            }
+            packet => (None, packet, buffer),
+        };
+
+        trace!("Packet({}={:?}={}) {:?}", id, state, self.peer_addr, packet);
+
+        // The client is hanging up, there is nothing left to answer
+        if let Packet::Disconnect { reason, message } = packet {
+            info!("Client ({}) disconnected: {:?} {}", id, reason, message);
+            outbound.close();
+            tx.disconnected(id);
+            return false;
+        }
+
+        if state == ClientState::Handshake {
+            let Packet::Hello {
+                protocol_version,
+                client_version,
+                capabilities,
+            } = packet
+            else {
+                self.reject(tx, format!("Expected Hello, received {:?}", packet));
+                return false;
+            };
+
+            if !(MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&protocol_version) {
+                self.reject(
+                    tx,
+                    format!(
+                        "Protocol version {} is not supported, expected {}..={}",
+                        protocol_version, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION
+                    ),
+                );
+                return false;
+            }
+
+            let capabilities = capabilities
+                .into_iter()
+                .filter(|c| SERVER_CAPABILITIES.contains(&c.as_str()))
+                .collect::<Vec<_>>();
+
+            info!(
+                "Client ({}) handshake: version {} protocol {} capabilities {:?}",
+                id, client_version, protocol_version, capabilities
+            );
+
+            let welcome = Packet::Welcome {
+                protocol_version: PROTOCOL_VERSION,
+                server_version: env!("CARGO_PKG_VERSION").to_owned(),
+                capabilities,
+            };
+            outbound.send(&welcome);
+            set_client_state(id, ClientState::Guest, clients);
+            return true;
+        }
+
+        if !state.allows(&packet) {
+            match RequestKind::of(&packet) {
+                Some(request) => send_error(
+                    id,
+                    request_id,
+                    clients,
+                    request,
+                    ErrorCode::InvalidState,
+                    format!("{:?} is not allowed while {:?}", request, state),
+                ),
+                None => debug!("Ingored Packaet {:?}", packet),
+            }
+            return true;
+        }
+
+        match packet {
+            Packet::LoginRequest { name, credentials } => {
+                if self.logged_in() && !self.names.allow_rename {
+                    send_error(
+                        id,
+                        request_id,
+                        clients,
+                        RequestKind::Login,
+                        ErrorCode::InvalidState,
+                        "Already logged in, renaming is not allowed".to_owned(),
+                    );
+                    return true;
+                }
+                self.logins.submit(
+                    Login {
+                        id,
+                        request_id,
+                        name,
+                        credentials,
+                        outbound: outbound.clone(),
+                        state: self.login.clone(),
+                    },
+                    clients,
+                );
+            }
+            Packet::RemoveFromListMatches => {
+                tx.request(Message::RemoveFromListMatches { id, request_id }, clients)
+            }
+            Packet::ListMatches { query } => tx.request(
+                Message::ListMatches {
+                    id,
+                    query,
+                    request_id,
+                },
+                clients,
+            ),
+            Packet::JoinMatch { room_id, password } => tx.request(
+                Message::JoinMatch {
+                    id,
+                    room_id,
+                    password,
+                    request_id,
+                },
+                clients,
+            ),
+            Packet::JoinByCode { code, password } => tx.request(
+                Message::JoinByCode {
+                    id,
+                    code,
+                    password,
+                    request_id,
+                },
+                clients,
+            ),
+            Packet::RegenerateJoinCode { room_id } => tx.request(
+                Message::RegenerateJoinCode {
+                    id,
+                    room_id,
+                    request_id,
+                },
+                clients,
+            ),
+            Packet::NewMatch { room_name, options } => tx.request(
+                Message::NewMatch {
+                    id,
+                    room_name,
+                    options,
+                    request_id,
+                },
+                clients,
+            ),
+            Packet::LeaveMatch { room_id } => tx.request(
+                Message::LeaveMatch {
+                    id,
+                    room_id,
+                    request_id,
+                },
+                clients,
+            ),
+            Packet::StartMatch { room_id, map } => tx.request(
+                Message::StartMatch {
+                    id,
+                    room_id,
+                    map,
+                    request_id,
+                },
+                clients,
+            ),
+            Packet::DeleteMatch { room_id } => {
+                debug!("Delete Match {} = {}", id, room_id);
+                tx.request(
+                    Message::DeleteMatch {
+                        id,
+                        room_id,
+                        request_id,
+                    },
+                    clients,
+                );
+            }
+            Packet::SpawnPlayers { room_id, positions } => tx.request(
+                Message::SpawnPlayers {
+                    id,
+                    room_id,
+                    positions,
+                    request_id,
+                },
+                clients,
+            ),
+            Packet::Ping => clients.write().unwrap().record_pong(id),
+            Packet::RemoteObjectCall {
+                id: target,
+                broadcast: false,
+                ..
+            } => {
+                Client::send_message_to(id, target, clients, buffer.as_slice());
+            }
+            Packet::DespawnRemoteObject {
+                id: owner,
+                object_id,
+            } => {
+                debug!(
+                    "Packet::DespawnRemoteObject {{ id: {}, object_id: {} }}",
+                    owner, object_id
+                );
+                Client::send_message(id, clients, buffer.as_slice());
+            }
+            _ => Client::send_message(id, clients, buffer.as_slice()),
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crossbeam::channel::Receiver;
+    use network_types::connection::{Credentials, MatchOptions};
+
+    use super::*;
+    use crate::{Anonymous, client::tests::connected, metrics::Metrics, outbound::Next};
+
+    struct Fixture {
+        session: Session,
+        tx: Dispatcher,
+        _rx: Receiver<Message>,
+        clients: Arc<RwLock<ClientRegistry>>,
+    }
+
+    impl Fixture {
+        /// A session for client 1 in `state`.
+        fn new(state: ClientState) -> Self {
+            let metrics = Arc::new(Metrics::default());
+            let (tx, _rx) = Dispatcher::new(16, metrics.clone());
+            let client = connected(1);
+            let outbound = client.outbound.clone();
+            let mut clients = ClientRegistry::new();
+            clients.insert(client);
+            clients.set_state(1, state);
+            let clients = Arc::new(RwLock::new(clients));
+            let names = Arc::new(NameRules::default());
+            let (logins, _) = Logins::spawn(
+                1,
+                4,
+                Arc::new(Anonymous),
+                names.clone(),
+                clients.clone(),
+                tx.clone(),
+                metrics,
+            );
+            let session = Session::new(1, "127.0.0.1:1".parse().unwrap(), &outbound, logins, names);
+            Self {
+                session,
+                tx,
+                _rx,
+                clients,
+            }
+        }
+
+        fn handle(&mut self, packet: Packet) -> bool {
+            let buffer = packet.serialize();
+            self.session
+                .handle_frame(packet, buffer, &self.tx, &self.clients)
+        }
+
+        /// The next packet queued for the client.
+        fn sent(&self) -> Option<Packet> {
+            match self.session.outbound.try_pop() {
+                Next::Frame(frame) => Some(Packet::try_from(&frame[4..]).unwrap()),
+                Next::Empty | Next::Closed => None,
+            }
+        }
+    }
+
+    fn new_match() -> Packet {
+        Packet::NewMatch {
+            room_name: "friday".to_owned(),
+            options: MatchOptions::default(),
+        }
+    }
+
+    fn hello(protocol_version: u16, capabilities: &[&str]) -> Packet {
+        Packet::Hello {
+            protocol_version,
+            client_version: "test".to_owned(),
+            capabilities: capabilities.iter().map(|c| c.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn hello_is_welcomed_with_the_common_capabilities() {
+        let mut fixture = Fixture::new(ClientState::Handshake);
+        assert!(fixture.handle(hello(PROTOCOL_VERSION, &["teleport"])));
+        match fixture.sent() {
+            Some(Packet::Welcome {
+                protocol_version,
+                capabilities,
+                ..
+            }) => {
+                assert_eq!(protocol_version, PROTOCOL_VERSION);
+                assert!(capabilities.is_empty());
+            }
+            other => panic!("expected Welcome, got {:?}", other),
+        }
+        assert_eq!(
+            Client::state_of(1, &fixture.clients),
+            Some(ClientState::Guest)
+        );
+    }
+
+    #[test]
+    fn unsupported_protocol_versions_are_rejected() {
+        for version in [MIN_PROTOCOL_VERSION - 1, PROTOCOL_VERSION + 1] {
+            let mut fixture = Fixture::new(ClientState::Handshake);
+            assert!(!fixture.handle(hello(version, &[])));
+            assert!(matches!(
+                fixture.sent(),
+                Some(Packet::HelloRejected { protocol_version, .. })
+                    if protocol_version == PROTOCOL_VERSION
+            ));
+            assert!(fixture.sent().is_none(), "nothing follows the rejection");
+            assert_eq!(
+                Client::state_of(1, &fixture.clients),
+                Some(ClientState::Handshake)
+            );
+        }
+    }
+
+    #[test]
+    fn anything_but_hello_during_the_handshake_is_rejected() {
+        let mut fixture = Fixture::new(ClientState::Handshake);
+        assert!(!fixture.handle(new_match()));
+        assert!(matches!(fixture.sent(), Some(Packet::HelloRejected { .. })));
+    }
+
+    #[test]
+    fn replies_to_requests_carry_their_id() {
+        let mut fixture = Fixture::new(ClientState::Guest);
+        assert!(fixture.handle(Packet::Request {
+            request_id: 7,
+            packet: Box::new(new_match()),
+        }));
+        match fixture.sent() {
+            Some(Packet::Response { request_id, packet }) => {
+                assert_eq!(request_id, 7);
+                assert!(matches!(
+                    *packet,
+                    Packet::Error {
+                        code: ErrorCode::InvalidState,
+                        ..
+                    }
+                ));
+            }
+            other => panic!("expected Response, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn nested_requests_are_protocol_violations() {
+        let mut fixture = Fixture::new(ClientState::Guest);
+        let nested = Packet::Request {
+            request_id: 8,
+            packet: Box::new(Packet::Ping),
+        };
+        assert!(!fixture.handle(Packet::Request {
+            request_id: 7,
+            packet: Box::new(nested),
+        }));
+        assert!(matches!(
+            fixture.sent(),
+            Some(Packet::Disconnect {
+                reason: DisconnectReason::ProtocolViolation,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn only_hello_is_allowed_during_the_handshake() {
+        let state = ClientState::Handshake;
+        assert!(state.allows(&Packet::Hello {
+            protocol_version: PROTOCOL_VERSION,
+            client_version: String::new(),
+            capabilities: vec![],
+        }));
+        assert!(!state.allows(&Packet::Ping));
+        assert!(!state.allows(&new_match()));
+    }
+
+    #[test]
+    fn guests_may_only_look_around_and_log_in() {
+        let state = ClientState::Guest;
+        assert!(state.allows(&Packet::LoginRequest {
+            name: "fred".to_owned(),
+            credentials: Credentials::None,
+        }));
+        assert!(state.allows(&Packet::RemoveFromListMatches));
+        assert!(!state.allows(&new_match()));
+        assert!(!state.allows(&Packet::LeaveMatch { room_id: 1 }));
+    }
+
+    #[test]
+    fn only_members_may_relay_and_only_hosts_may_start() {
+        let start = Packet::StartMatch {
+            room_id: 1,
+            map: String::new(),
+        };
+        let leave = Packet::LeaveMatch { room_id: 1 };
+        let message = Packet::Message {
+            id: 1,
+            name: "fred".to_owned(),
+            text: "hi".to_owned(),
+        };
+
+        assert!(ClientState::Menu.allows(&new_match()));
+        assert!(!ClientState::Menu.allows(&leave));
+        assert!(!ClientState::Menu.allows(&message));
+
+        assert!(ClientState::MatchClient.allows(&leave));
+        assert!(ClientState::MatchClient.allows(&message));
+        assert!(!ClientState::MatchClient.allows(&start));
+        assert!(!ClientState::MatchClient.allows(&new_match()));
+
+        assert!(ClientState::MatchHost.allows(&start));
+        assert!(ClientState::MatchHost.allows(&message));
+        assert!(!ClientState::InGame.allows(&start));
+        assert!(ClientState::InGame.allows(&message));
+    }
+
+    #[test]
+    fn requests_not_allowed_are_refused_with_invalid_state() {
+        let mut fixture = Fixture::new(ClientState::Guest);
+        assert!(fixture.handle(new_match()));
+        match fixture.sent() {
+            Some(Packet::Error { request, code, .. }) => {
+                assert_eq!(request, RequestKind::NewMatch);
+                assert_eq!(code, ErrorCode::InvalidState);
+            }
+            other => panic!("expected Error, got {:?}", other),
+        }
+        assert_eq!(
+            Client::state_of(1, &fixture.clients),
+            Some(ClientState::Guest)
+        );
+    }
+}
@@ -43,7 +43,7 @@ pub fn client(server_addr: String) -> std::io::Result<Vec<JoinHandle<()>>> {
             // print!("> ");
             // io::stdout().flush()?;
 
-            let mut input = "String::new()".to_owned();
+            let input = "String::new()".to_owned();
             // stdin.read_line(&mut input)?;
 
             if input.trim().is_empty() {
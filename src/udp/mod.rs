@@ -1,5 +1,8 @@
+// Manual test client, kept around for debugging relays.
+#[allow(dead_code)]
 mod client;
+mod relay;
 mod server;
 
-pub use client::client;
+pub use relay::Relay;
 pub use server::server;
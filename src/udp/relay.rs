@@ -0,0 +1,150 @@
+use log::debug;
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::net::SocketAddr;
+
+use crate::helpers;
+
+/// Which addresses the UDP relay forwards between. Addresses are only let
+/// into a match with a token issued to one of its members over TCP, see
+/// [`Relay::issue`], and are dropped again once that token is revoked.
+#[derive(Debug, Default)]
+pub struct Relay {
+    /// Match each outstanding token lets its holder into.
+    tokens: HashMap<u64, i32>,
+    /// Address each token registered, if any.
+    bound: HashMap<u64, SocketAddr>,
+    /// Token and match of each registered address.
+    addrs: HashMap<SocketAddr, (u64, i32)>,
+    /// Registered addresses by match.
+    rooms: HashMap<i32, Vec<SocketAddr>>,
+}
+
+impl Relay {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A new token letting its holder into match `match_id`.
+    pub fn issue(&mut self, match_id: i32) -> u64 {
+        loop {
+            let token = helpers::secret_u64();
+            if let Entry::Vacant(entry) = self.tokens.entry(token) {
+                entry.insert(match_id);
+                return token;
+            }
+        }
+    }
+
+    /// Stops `token` from working and forgets the address it registered.
+    pub fn revoke(&mut self, token: u64) {
+        self.tokens.remove(&token);
+        if let Some(addr) = self.bound.remove(&token) {
+            self.unbind(addr);
+        }
+    }
+
+    /// Registers `addr` with match `match_id` if `token` was issued for it.
+    /// A token only ever holds one address: registering again, say after a
+    /// NAT rebinding, replaces the old one.
+    pub fn register(&mut self, token: u64, match_id: i32, addr: SocketAddr) -> bool {
+        if self.tokens.get(&token) != Some(&match_id) {
+            return false;
+        }
+        if self.bound.get(&token) == Some(&addr) {
+            return true;
+        }
+        if let Some(old) = self.bound.insert(token, addr) {
+            self.unbind(old);
+        }
+        if let Some((other, _)) = self.addrs.get(&addr).copied() {
+            self.bound.remove(&other);
+            self.unbind(addr);
+        }
+        debug!("Relay address {} registered on room {}", addr, match_id);
+        self.addrs.insert(addr, (token, match_id));
+        self.rooms.entry(match_id).or_default().push(addr);
+        true
+    }
+
+    fn unbind(&mut self, addr: SocketAddr) {
+        let Some((_, match_id)) = self.addrs.remove(&addr) else {
+            return;
+        };
+        if let Some(list) = self.rooms.get_mut(&match_id) {
+            list.retain(|a| *a != addr);
+            if list.is_empty() {
+                self.rooms.remove(&match_id);
+            }
+        }
+    }
+
+    /// The match `addr` is registered with.
+    pub fn match_of(&self, addr: SocketAddr) -> Option<i32> {
+        self.addrs.get(&addr).map(|(_, match_id)| *match_id)
+    }
+
+    /// Addresses registered with match `match_id`, apart from `src`.
+    pub fn peers(&self, match_id: i32, src: SocketAddr) -> Vec<SocketAddr> {
+        self.rooms
+            .get(&match_id)
+            .into_iter()
+            .flatten()
+            .copied()
+            .filter(|addr| *addr != src)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    #[test]
+    fn register_needs_a_token_for_the_match() {
+        let mut relay = Relay::new();
+        let token = relay.issue(1);
+
+        assert!(!relay.register(token, 2, addr(1000)));
+        assert!(!relay.register(token ^ 1, 1, addr(1000)));
+        assert_eq!(relay.match_of(addr(1000)), None);
+
+        assert!(relay.register(token, 1, addr(1000)));
+        assert_eq!(relay.match_of(addr(1000)), Some(1));
+    }
+
+    #[test]
+    fn registering_again_replaces_the_address() {
+        let mut relay = Relay::new();
+        let first = relay.issue(1);
+        let second = relay.issue(1);
+        assert!(relay.register(first, 1, addr(1000)));
+        assert!(relay.register(second, 1, addr(2000)));
+        assert_eq!(relay.peers(1, addr(1000)), [addr(2000)]);
+
+        assert!(relay.register(first, 1, addr(1001)));
+        assert_eq!(relay.match_of(addr(1000)), None);
+        assert_eq!(relay.peers(1, addr(2000)), [addr(1001)]);
+    }
+
+    #[test]
+    fn revoke_forgets_the_address() {
+        let mut relay = Relay::new();
+        let first = relay.issue(1);
+        let second = relay.issue(1);
+        assert!(relay.register(first, 1, addr(1000)));
+        assert!(relay.register(second, 1, addr(2000)));
+
+        relay.revoke(first);
+        assert_eq!(relay.match_of(addr(1000)), None);
+        assert_eq!(relay.peers(1, addr(2000)), []);
+        assert!(!relay.register(first, 1, addr(1000)));
+
+        relay.revoke(second);
+        assert!(relay.rooms.is_empty());
+    }
+}
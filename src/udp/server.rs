@@ -1,13 +1,16 @@
-use crossbeam::channel::{Receiver, Sender, unbounded};
+use crossbeam::channel::{Receiver, Sender, TrySendError, bounded};
+use log::{debug, error, info, warn};
+use mio::{Events, Interest, Poll, Token};
 use network_types::connection::Packet;
-use std::collections::HashMap;
+use std::io::ErrorKind;
 use std::net::{SocketAddr, UdpSocket};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, RwLock};
 use std::thread::{self, JoinHandle};
 use std::time::Duration;
 
-type Clients = Arc<RwLock<HashMap<i32, Vec<SocketAddr>>>>;
+use super::Relay;
+use crate::metrics::Metrics;
 
 #[derive(Clone, Debug)]
 struct Message {
@@ -16,78 +19,106 @@ struct Message {
     data: Vec<u8>,
 }
 
-pub fn server(endpoint: String, running: Arc<AtomicBool>) -> std::io::Result<Vec<JoinHandle<()>>> {
-    let socket = UdpSocket::bind(endpoint.as_str())?;
+/// Starts the relay with `workers` relaying threads and returns the address
+/// it is bound to. Datagrams longer than `buffer_size` are truncated.
+/// Datagrams that arrive while `capacity` of them are already waiting for a
+/// worker are dropped and counted in `metrics`.
+///
+/// Only addresses registered with a [`Packet::RelayToken`] are relayed, to
+/// the other addresses registered with the same match in `relay`.
+///
+/// The threads stop once `running` is cleared.
+pub fn server(
+    endpoint: &str,
+    relay: Arc<RwLock<Relay>>,
+    running: Arc<AtomicBool>,
+    workers: usize,
+    buffer_size: usize,
+    capacity: usize,
+    metrics: Arc<Metrics>,
+) -> std::io::Result<(SocketAddr, Vec<JoinHandle<()>>)> {
+    let socket = UdpSocket::bind(endpoint)?;
     socket.set_nonblocking(true)?;
+    let local_addr = socket.local_addr()?;
 
-    println!("UDP server on {}", endpoint);
-
-    let clients: Clients = Arc::new(RwLock::new(HashMap::new()));
+    info!("UDP server on {}", local_addr);
 
     // Channel for dispatching received packets
-    let (tx, rx): (Sender<Message>, Receiver<Message>) = unbounded();
+    let (tx, rx): (Sender<Message>, Receiver<Message>) = bounded(capacity);
+    metrics.udp_relay.set_capacity(capacity);
 
     let mut join_handlers: Vec<JoinHandle<()>> = Vec::new();
 
     //
-    // THREAD 1: RECEIVE THREAD (NON-BLOCKING UDP POLLING)
+    // THREAD 1: RECEIVE THREAD (WAKES UP ON READINESS)
     //
     {
-        let socket = socket.try_clone()?;
+        let mut socket = mio::net::UdpSocket::from_std(socket.try_clone()?);
+        let mut poll = Poll::new()?;
+        poll.registry()
+            .register(&mut socket, Token(0), Interest::READABLE)?;
         let tx = tx.clone();
-        let clients = Arc::clone(&clients);
+        let relay = Arc::clone(&relay);
 
         let server_running = running.clone();
         join_handlers.push(thread::spawn(move || {
-            let mut buf = [0u8; 2048];
+            let mut buf = vec![0u8; buffer_size];
 
-            let mut cache = HashMap::new();
+            let mut events = Events::with_capacity(16);
 
             while server_running.load(Ordering::Relaxed) {
-                match socket.recv_from(&mut buf) {
-                    Ok((len, src)) => {
-                        if cache.contains_key(&src) {
-                            // by pass match
-                            // Send message to workers
-                            let match_id = cache[&src];
-                            tx.send(Message {
-                                src,
-                                match_id,
-                                data: buf[..len].to_vec(),
-                            })
-                            .ok();
-                            continue;
-                        }
-
-                        if let Ok(packet) = Packet::try_from(&buf[..len]) {
-                            match packet {
-                                Packet::JoinMatch { room_id } => {
-                                    if !cache.contains_key(&src) {
-                                        cache.insert(src, room_id);
-                                        let mut list = clients.write().unwrap();
-
-                                        println!("=============================================");
-                                        println!("New client: {} on room: {}", src, room_id);
-                                        println!("=============================================");
-                                        list.entry(room_id)
-                                            .or_insert_with(|| Vec::new())
-                                            .push(src.clone());
-                                    }
+                // Wakes up now and then to notice that the server is stopping
+                if let Err(err) = poll.poll(&mut events, Some(Duration::from_millis(100))) {
+                    if err.kind() != ErrorKind::Interrupted {
+                        error!("UDP poll failed: {}", err);
+                        break;
+                    }
+                    continue;
+                }
 
-                                    let ping = Packet::Ping.serialize();
-                                    socket.send_to(ping.as_slice(), src).ok();
-                                    socket.send_to(ping.as_slice(), src).ok();
-                                    socket.send_to(ping.as_slice(), src).ok();
-                                }
-                                _ => {
-                                    eprintln!("Reach invalid Packet with a unlogged user {}", src);
+                // Readiness is only reported once, drain everything that arrived
+                loop {
+                    match socket.recv_from(&mut buf) {
+                        Ok((len, src)) => {
+                            let registered = relay.read().unwrap().match_of(src);
+                            if let Some(match_id) = registered {
+                                // Send message to workers
+                                match tx.try_send(Message {
+                                    src,
+                                    match_id,
+                                    data: buf[..len].to_vec(),
+                                }) {
+                                    Ok(()) => metrics.udp_relay.observe(tx.len()),
+                                    // Late gameplay updates are worthless, drop them
+                                    Err(TrySendError::Full(_)) => metrics.udp_relay.overflow(),
+                                    Err(TrySendError::Disconnected(_)) => {}
                                 }
+                                continue;
+                            }
+
+                            match Packet::try_from(&buf[..len]) {
+                                Ok(packet) => match packet {
+                                    Packet::RelayToken { room_id, token } => {
+                                        if !relay.write().unwrap().register(token, room_id, src) {
+                                            warn!("Refused relay token from {}", src);
+                                            continue;
+                                        }
+                                        info!("New client: {} on room: {}", src, room_id);
+
+                                        let ping = Packet::Ping.serialize();
+                                        socket.send_to(ping.as_slice(), src).ok();
+                                        socket.send_to(ping.as_slice(), src).ok();
+                                        socket.send_to(ping.as_slice(), src).ok();
+                                    }
+                                    _ => {
+                                        warn!("Reach invalid Packet with a unlogged user {}", src);
+                                    }
+                                },
+                                Err(err) => warn!("Invalid Packet from {}: {}", src, err),
                             }
                         }
-                    }
-                    Err(_) => {
-                        // No data, avoid busy loop
-                        thread::sleep(Duration::from_millis(2));
+                        Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                        Err(err) => warn!("UDP receive failed: {}", err),
                     }
                 }
             }
@@ -97,40 +128,29 @@ pub fn server(endpoint: String, running: Arc<AtomicBool>) -> std::io::Result<Vec
     //
     // THREAD POOL: WORKERS FOR PROCESSING + RELAYING
     //
-    let thread_count = 4; // adjust based on CPU cores
-
-    for id in 0..thread_count {
+    for id in 0..workers {
         let socket = socket.try_clone()?;
-        let clients = Arc::clone(&clients);
+        let relay = Arc::clone(&relay);
         let rx = rx.clone();
 
-        let current_thread_running = running.clone();
         join_handlers.push(thread::spawn(move || {
-            println!("Worker thread {} started.", id);
-
-            while current_thread_running.load(Ordering::Relaxed) {
-                if let Ok(msg) = rx.recv() {
-                    let targets: Vec<SocketAddr> = {
-                        let list = clients.read().unwrap();
-                        list[&msg.match_id]
-                            .iter()
-                            .cloned()
-                            .filter(|addr| *addr != msg.src)
-                            .collect()
-                    };
-
-                    // Relay to all other clients
-                    for addr in targets {
-                        match socket.send_to(&msg.data, addr) {
-                            Ok(_) => continue,
-                            // TODO Handle x errors to remove client from room
-                            Err(err) => println!("Error sending {:?} for {:?}", err, addr),
-                        }
+            debug!("Worker thread {} started.", id);
+
+            // Ends once the receive thread stops and drops its sender
+            while let Ok(msg) = rx.recv() {
+                let targets = relay.read().unwrap().peers(msg.match_id, msg.src);
+
+                // Relay to all other clients
+                for addr in targets {
+                    match socket.send_to(&msg.data, addr) {
+                        Ok(_) => continue,
+                        // TODO Handle x errors to remove client from room
+                        Err(err) => warn!("Error sending {:?} for {:?}", err, addr),
                     }
                 }
             }
         }));
     }
 
-    Ok(join_handlers)
+    Ok((local_addr, join_handlers))
 }
@@ -0,0 +1,107 @@
+// Each test binary uses only some of these helpers
+#![allow(dead_code)]
+
+use std::{
+    io::{Read, Write},
+    net::{SocketAddr, TcpStream},
+    time::Duration,
+};
+
+use network_types::connection::{
+    Credentials, DisconnectReason, MatchOptions, PROTOCOL_VERSION, Packet,
+};
+
+/// A test client speaking the lobby protocol over TCP.
+pub struct Peer {
+    pub stream: TcpStream,
+}
+
+impl Peer {
+    pub fn connect(addr: SocketAddr) -> Self {
+        let stream = TcpStream::connect(addr).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        Self { stream }
+    }
+
+    pub fn send(&mut self, packet: Packet) {
+        self.stream
+            .write_all(&packet.serialize_with_header())
+            .unwrap();
+    }
+
+    /// The next packet other than a heartbeat.
+    pub fn recv(&mut self) -> Packet {
+        loop {
+            let mut header = [0; 4];
+            self.stream.read_exact(&mut header).unwrap();
+            let mut payload = vec![0; u32::from_be_bytes(header) as usize];
+            self.stream.read_exact(&mut payload).unwrap();
+            match Packet::try_from(&payload).unwrap() {
+                Packet::Ping => continue,
+                packet => return packet,
+            }
+        }
+    }
+
+    pub fn hello(&mut self) {
+        self.send(Packet::Hello {
+            protocol_version: PROTOCOL_VERSION,
+            client_version: "test".to_owned(),
+            capabilities: vec![],
+        });
+        match self.recv() {
+            Packet::Welcome {
+                protocol_version, ..
+            } => assert_eq!(protocol_version, PROTOCOL_VERSION),
+            other => panic!("expected Welcome, got {:?}", other),
+        }
+    }
+
+    /// Says hello and logs in as `name`, returning the id given.
+    pub fn log_in(&mut self, name: &str) -> i32 {
+        self.hello();
+        self.send(Packet::LoginRequest {
+            name: name.to_owned(),
+            credentials: Credentials::None,
+        });
+        match self.recv() {
+            Packet::Login { id, name: given } => {
+                assert_eq!(given, name);
+                id
+            }
+            other => panic!("expected Login, got {:?}", other),
+        }
+    }
+
+    /// Creates a match with default options, returning its id.
+    pub fn create_match(&mut self, room_name: &str) -> i32 {
+        self.send(Packet::NewMatch {
+            room_name: room_name.to_owned(),
+            options: MatchOptions::default(),
+        });
+        let match_id = match self.recv() {
+            Packet::MatchCreated { id, .. } => id,
+            other => panic!("expected MatchCreated, got {:?}", other),
+        };
+        self.expect_relay_token(match_id);
+        match_id
+    }
+
+    /// Skips whatever comes before the disconnect and returns its reason.
+    pub fn expect_disconnect(&mut self) -> DisconnectReason {
+        loop {
+            if let Packet::Disconnect { reason, .. } = self.recv() {
+                return reason;
+            }
+        }
+    }
+
+    pub fn expect_relay_token(&mut self, match_id: i32) {
+        match self.recv() {
+            Packet::RelayToken { room_id, .. } => assert_eq!(room_id, match_id),
+            other => panic!("expected RelayToken, got {:?}", other),
+        }
+    }
+}
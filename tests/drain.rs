@@ -0,0 +1,78 @@
+mod common;
+
+use std::{sync::mpsc, time::Duration};
+
+use common::Peer;
+use network_manager::ServerBuilder;
+use network_types::connection::{ErrorCode, MatchOptions, MatchQuery, Packet};
+
+fn expect_refusal(peer: &mut Peer) -> ErrorCode {
+    match peer.recv() {
+        Packet::Error { code, .. } => code,
+        other => panic!("expected Error, got {:?}", other),
+    }
+}
+
+/// Skips match list updates until the next snapshot and returns its
+/// draining flag.
+fn expect_draining(peer: &mut Peer) -> bool {
+    loop {
+        match peer.recv() {
+            Packet::MatchList { draining, .. } => return draining,
+            Packet::MatchAdded { .. }
+            | Packet::MatchUpdated { .. }
+            | Packet::MatchRemoved { .. } => {}
+            other => panic!("expected MatchList, got {:?}", other),
+        }
+    }
+}
+
+#[test]
+fn draining_refuses_new_players_until_the_last_match_ends() {
+    let (drains, drained) = mpsc::channel();
+    let server = ServerBuilder::new()
+        .tcp_addr("127.0.0.1:0")
+        .udp_addr("127.0.0.1:0")
+        .shutdown_grace(Duration::ZERO)
+        .on_drained(move || {
+            let _ = drains.send(());
+        })
+        .start()
+        .unwrap();
+    let addr = server.local_addrs().tcp;
+
+    let mut host = Peer::connect(addr);
+    host.log_in("host");
+    let match_id = host.create_match("friday");
+    let mut guest = Peer::connect(addr);
+    guest.log_in("guest");
+
+    server.set_draining(true);
+    guest.send(Packet::NewMatch {
+        room_name: "saturday".to_owned(),
+        options: MatchOptions::default(),
+    });
+    assert_eq!(expect_refusal(&mut guest), ErrorCode::Draining);
+    guest.send(Packet::JoinMatch {
+        room_id: match_id,
+        password: None,
+    });
+    assert_eq!(expect_refusal(&mut guest), ErrorCode::Draining);
+    guest.send(Packet::ListMatches {
+        query: MatchQuery::default(),
+    });
+    assert!(expect_draining(&mut guest));
+
+    // The running match goes on until its last member leaves
+    assert!(drained.try_recv().is_err());
+    host.send(Packet::LeaveMatch { room_id: match_id });
+    assert_eq!(drained.recv_timeout(Duration::from_secs(5)), Ok(()));
+
+    // Subscribers are told when draining is called off
+    server.set_draining(false);
+    assert!(!expect_draining(&mut guest));
+    guest.send(Packet::RemoveFromListMatches);
+    guest.create_match("saturday");
+
+    server.shutdown().unwrap();
+}
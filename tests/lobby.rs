@@ -0,0 +1,123 @@
+mod common;
+
+use std::{sync::mpsc, time::Duration};
+
+use common::Peer;
+use network_manager::{IoMode, ServerBuilder};
+use network_types::connection::{MatchOptions, Packet};
+
+fn create_join_and_disconnect(io_mode: IoMode) {
+    let (disconnects, disconnected) = mpsc::channel();
+    let server = ServerBuilder::new()
+        .tcp_addr("127.0.0.1:0")
+        .udp_addr("127.0.0.1:0")
+        .io_mode(io_mode)
+        .on_disconnect(move |id| {
+            let _ = disconnects.send(id);
+        })
+        .start()
+        .unwrap();
+    let addr = server.local_addrs().tcp;
+
+    let mut host = Peer::connect(addr);
+    let host_id = host.log_in("host");
+    host.send(Packet::NewMatch {
+        room_name: "friday".to_owned(),
+        options: MatchOptions::default(),
+    });
+    let match_id = match host.recv() {
+        Packet::MatchCreated {
+            id,
+            owner_id,
+            room_name,
+            join_code,
+        } => {
+            assert_eq!(owner_id, host_id);
+            assert_eq!(room_name, "friday");
+            assert!(!join_code.is_empty());
+            id
+        }
+        other => panic!("expected MatchCreated, got {:?}", other),
+    };
+    host.expect_relay_token(match_id);
+
+    let mut guest = Peer::connect(addr);
+    let guest_id = guest.log_in("guest");
+    assert_ne!(guest_id, host_id);
+    guest.send(Packet::JoinMatch {
+        room_id: match_id,
+        password: None,
+    });
+    let mut members = Vec::new();
+    for _ in 0..2 {
+        match guest.recv() {
+            Packet::MatchJoined {
+                id,
+                user_id,
+                room_name,
+                ..
+            } => {
+                assert_eq!(id, match_id);
+                assert_eq!(room_name, "friday");
+                members.push(user_id);
+            }
+            other => panic!("expected MatchJoined, got {:?}", other),
+        }
+    }
+    members.sort();
+    assert_eq!(members, [host_id, guest_id]);
+    guest.expect_relay_token(match_id);
+    match host.recv() {
+        Packet::MatchJoined {
+            user_id, user_name, ..
+        } => {
+            assert_eq!(user_id, guest_id);
+            assert_eq!(user_name, "guest");
+        }
+        other => panic!("expected MatchJoined, got {:?}", other),
+    }
+
+    // The guest is left alone in the match and takes it over
+    drop(host);
+    assert_eq!(
+        disconnected.recv_timeout(Duration::from_secs(5)),
+        Ok(host_id)
+    );
+    let mut left = false;
+    let mut host_changed = false;
+    while !(left && host_changed) {
+        match guest.recv() {
+            Packet::MatchLeaved { user_id, .. } => {
+                assert_eq!(user_id, host_id);
+                left = true;
+            }
+            Packet::HostChanged {
+                room_id,
+                new_owner_id,
+            } => {
+                assert_eq!(room_id, match_id);
+                assert_eq!(new_owner_id, guest_id);
+                host_changed = true;
+            }
+            Packet::JoinCode { room_id, .. } => assert_eq!(room_id, match_id),
+            other => panic!("expected the host to leave, got {:?}", other),
+        }
+    }
+
+    drop(guest);
+    assert_eq!(
+        disconnected.recv_timeout(Duration::from_secs(5)),
+        Ok(guest_id)
+    );
+    server.shutdown().unwrap();
+}
+
+#[test]
+fn create_join_and_disconnect_with_event_loop() {
+    create_join_and_disconnect(IoMode::EventLoop);
+}
+
+#[test]
+fn create_join_and_disconnect_with_blocking_threads() {
+    create_join_and_disconnect(IoMode::Blocking);
+}
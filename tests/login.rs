@@ -0,0 +1,134 @@
+mod common;
+
+use std::{
+    io::Read,
+    sync::{Arc, Condvar, Mutex},
+    time::Duration,
+};
+
+use common::Peer;
+use network_manager::{AuthError, Authenticator, IoMode, ServerBuilder};
+use network_types::connection::{
+    Credentials, DisconnectReason, ErrorCode, MatchOptions, Packet, RequestKind,
+};
+
+/// Accepts any password but "wrong", once the test opens the gate.
+#[derive(Default)]
+struct Gate {
+    open: Mutex<bool>,
+    opened: Condvar,
+}
+
+impl Gate {
+    fn open(&self) {
+        *self.open.lock().unwrap() = true;
+        self.opened.notify_all();
+    }
+}
+
+impl Authenticator for Gate {
+    fn authenticate(&self, name: &str, credentials: &Credentials) -> Result<String, AuthError> {
+        let open = self.open.lock().unwrap();
+        let _open = self.opened.wait_while(open, |open| !*open).unwrap();
+        match credentials {
+            Credentials::Password(password) if password == "wrong" => Err(AuthError::Rejected),
+            _ => Ok(name.to_owned()),
+        }
+    }
+}
+
+fn log_in_with(peer: &mut Peer, password: &str) {
+    peer.send(Packet::LoginRequest {
+        name: "fred".to_owned(),
+        credentials: Credentials::Password(password.to_owned()),
+    });
+}
+
+fn slow_login_blocks_nobody_else(io_mode: IoMode) {
+    let gate = Arc::new(Gate::default());
+    let server = ServerBuilder::new()
+        .tcp_addr("127.0.0.1:0")
+        .udp_addr("127.0.0.1:0")
+        .io_mode(io_mode)
+        .authenticator(gate.clone())
+        .shutdown_grace(Duration::ZERO)
+        .start()
+        .unwrap();
+    let addr = server.local_addrs().tcp;
+
+    // A request sent right behind the login is only read once it is done
+    let mut slow = Peer::connect(addr);
+    slow.hello();
+    log_in_with(&mut slow, "secret");
+    slow.send(Packet::NewMatch {
+        room_name: "friday".to_owned(),
+        options: MatchOptions::default(),
+    });
+
+    let mut other = Peer::connect(addr);
+    other.hello();
+
+    gate.open();
+    match slow.recv() {
+        Packet::Login { name, .. } => assert_eq!(name, "fred"),
+        other => panic!("expected Login, got {:?}", other),
+    }
+    match slow.recv() {
+        Packet::MatchCreated { room_name, .. } => assert_eq!(room_name, "friday"),
+        other => panic!("expected MatchCreated, got {:?}", other),
+    }
+    server.shutdown().unwrap();
+}
+
+#[test]
+fn slow_login_blocks_nobody_else_with_event_loop() {
+    slow_login_blocks_nobody_else(IoMode::EventLoop);
+}
+
+#[test]
+fn slow_login_blocks_nobody_else_with_blocking_threads() {
+    slow_login_blocks_nobody_else(IoMode::Blocking);
+}
+
+fn failed_logins_disconnect(io_mode: IoMode) {
+    let gate = Arc::new(Gate::default());
+    gate.open();
+    let server = ServerBuilder::new()
+        .tcp_addr("127.0.0.1:0")
+        .udp_addr("127.0.0.1:0")
+        .io_mode(io_mode)
+        .authenticator(gate)
+        .start()
+        .unwrap();
+
+    let mut peer = Peer::connect(server.local_addrs().tcp);
+    peer.hello();
+    for _ in 0..5 {
+        log_in_with(&mut peer, "wrong");
+    }
+    for _ in 0..5 {
+        match peer.recv() {
+            Packet::Error { request, code, .. } => {
+                assert_eq!(request, RequestKind::Login);
+                assert_eq!(code, ErrorCode::AuthenticationFailed);
+            }
+            other => panic!("expected Error, got {:?}", other),
+        }
+    }
+    match peer.recv() {
+        Packet::Disconnect { reason, .. } => assert_eq!(reason, DisconnectReason::TooManyAttempts),
+        other => panic!("expected Disconnect, got {:?}", other),
+    }
+    assert_eq!(peer.stream.read(&mut [0; 1]).unwrap(), 0);
+    server.shutdown().unwrap();
+}
+
+#[test]
+fn failed_logins_disconnect_with_event_loop() {
+    failed_logins_disconnect(IoMode::EventLoop);
+}
+
+#[test]
+fn failed_logins_disconnect_with_blocking_threads() {
+    failed_logins_disconnect(IoMode::Blocking);
+}
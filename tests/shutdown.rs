@@ -0,0 +1,67 @@
+mod common;
+
+use std::{
+    thread,
+    time::{Duration, Instant},
+};
+
+use common::Peer;
+use network_manager::{IoMode, ServerBuilder};
+use network_types::connection::{DisconnectReason, Packet};
+
+fn shutdown_waits_for_matches(io_mode: IoMode) {
+    let grace = Duration::from_secs(10);
+    let server = ServerBuilder::new()
+        .tcp_addr("127.0.0.1:0")
+        .udp_addr("127.0.0.1:0")
+        .io_mode(io_mode)
+        .shutdown_grace(grace)
+        .start()
+        .unwrap();
+    let addr = server.local_addrs().tcp;
+
+    let mut host = Peer::connect(addr);
+    host.log_in("host");
+    let match_id = host.create_match("friday");
+    let mut idle = Peer::connect(addr);
+    idle.log_in("idle");
+
+    let started = Instant::now();
+    let shutdown = thread::spawn(move || server.shutdown());
+
+    // Nothing to wait for outside of a match
+    assert_eq!(idle.expect_disconnect(), DisconnectReason::ServerShutdown);
+
+    // The match ending lets the shutdown finish before the grace period
+    host.send(Packet::LeaveMatch { room_id: match_id });
+    assert_eq!(host.expect_disconnect(), DisconnectReason::ServerShutdown);
+    shutdown.join().unwrap().unwrap();
+    assert!(started.elapsed() < grace);
+}
+
+#[test]
+fn shutdown_waits_for_matches_with_event_loop() {
+    shutdown_waits_for_matches(IoMode::EventLoop);
+}
+
+#[test]
+fn shutdown_waits_for_matches_with_blocking_threads() {
+    shutdown_waits_for_matches(IoMode::Blocking);
+}
+
+#[test]
+fn shutdown_ends_matches_after_the_grace_period() {
+    let server = ServerBuilder::new()
+        .tcp_addr("127.0.0.1:0")
+        .udp_addr("127.0.0.1:0")
+        .shutdown_grace(Duration::from_millis(100))
+        .start()
+        .unwrap();
+
+    let mut host = Peer::connect(server.local_addrs().tcp);
+    host.log_in("host");
+    host.create_match("friday");
+
+    server.shutdown().unwrap();
+    assert_eq!(host.expect_disconnect(), DisconnectReason::ServerShutdown);
+}